@@ -10,7 +10,11 @@ use std::sync::Arc;
 use std::thread::sleep;
 use std::time::{Duration, Instant};
 
+/// Time the router gets after its shutdown timeout to write its snapshot and log
+const ROUTER_SHUTDOWN_MARGIN: Duration = Duration::from_secs(5);
+
 #[derive(Debug)]
+#[allow(clippy::enum_variant_names)]
 enum LauncherError {
     TGBotError,
     ServerError,
@@ -64,6 +68,10 @@ struct Args {
     tg_token: String,
     #[clap(long, short, default_value = "false")]
     debug: bool,
+    /// Time in milliseconds the router gets on shutdown to answer the requests it
+    /// has, before the rest is snapshotted or dropped
+    #[clap(long, default_value = "10000")]
+    router_shutdown_timeout_ms: u64,
 }
 
 impl Args {
@@ -75,6 +83,8 @@ impl Args {
                 format!("{}:{}", self.reply_server_address, self.reply_server_port),
                 "--grpc-address".to_string(),
                 format!("127.0.0.1:{}", self.grpc_port),
+                "--shutdown-timeout-ms".to_string(),
+                self.router_shutdown_timeout_ms.to_string(),
             ],
             ProgramName::TGBot => vec![
                 "--reply-server-address".to_string(),
@@ -121,9 +131,13 @@ fn main() -> Result<(), LauncherError> {
         }
     }
 
+    // The router drains its queue into the gRPC server, so it goes first and gets its
+    // whole shutdown timeout, plus time to save what is left
+    let router_timeout =
+        Duration::from_millis(args.router_shutdown_timeout_ms) + ROUTER_SHUTDOWN_MARGIN;
+    terminate("router", router, router_timeout).expect("Failed to terminate router");
     terminate("grpc_server", grpc_server, Duration::from_millis(100))
         .expect("Failed to terminate grpc_server");
-    terminate("router", router, Duration::from_millis(100)).expect("Failed to terminate router");
     terminate("telegram_bot", tg_bot, Duration::from_millis(100))
         .expect("Failed to terminate telegram_bot");
 
@@ -136,7 +150,7 @@ fn spawn_program(args: &Args, program_name: &str) -> Result<Child, LauncherError
 
     let mut prefix = env::current_exe().map_err(|e| {
         eprintln!("Failed to get executable path: {}", e);
-        return program_type.get_error();
+        program_type.get_error()
     })?;
     prefix = prefix.parent().unwrap().to_path_buf();
 
@@ -0,0 +1,480 @@
+//! Throughput of the request queue, from appending requests to handing their batches
+//! to the batching task, against the request/response actor and queue state it
+//! replaced.
+//!
+//! Every iteration appends a burst of requests as fast as possible and waits until a
+//! consumer that answers immediately has responded to all of them, so the reported
+//! rate is the highest request rate the queue keeps up with.
+//!
+//! Run with `cargo bench -p router --bench queue`.
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use router::policy::{BatchPolicy, BatchingConfig, BudgetUnit, PolicyKind};
+use router::queue::{Bucketing, Queue, QueueConfig, QueueEntry, ResponseRx, TenantWeights};
+use router::{Priority, TextReplyRequest, TextReplyResponse};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::Instant;
+
+const MAX_CONCURRENT_BATCHES: usize = 4;
+
+fn policy() -> Arc<dyn BatchPolicy> {
+    BatchingConfig {
+        max_batch_size: 32,
+        max_wait: Duration::from_millis(5),
+        max_batch_budget: usize::MAX,
+        budget_unit: BudgetUnit::Bytes,
+        latency_target: Duration::from_millis(200),
+    }
+    .policy(PolicyKind::SizeOrTimeout)
+}
+
+fn config() -> QueueConfig {
+    QueueConfig {
+        max_depth: usize::MAX,
+        bucketing: Bucketing {
+            boundaries: Vec::new(),
+            unit: BudgetUnit::Bytes,
+            starvation: Duration::from_secs(1),
+        },
+        priority_aging: Duration::ZERO,
+        tenant_weights: TenantWeights::default(),
+        dedup: false,
+    }
+}
+
+fn entry(i: usize) -> (QueueEntry, ResponseRx) {
+    let now = Instant::now();
+    let request = TextReplyRequest {
+        message: format!("message {i}"),
+        timeout_ms: None,
+        priority: Priority::Normal,
+        tenant: Some(format!("tenant-{}", i % 8)),
+    };
+    QueueEntry::new(request, now, now + Duration::from_secs(60))
+}
+
+fn respond(entries: HashMap<u64, QueueEntry>) {
+    let batch_size = entries.len() as u32;
+    for entry in entries.into_values() {
+        entry.respond(Ok(TextReplyResponse {
+            message: String::new(),
+            batch_id: 0,
+            request_id: 0,
+            batch_size,
+            processing_time: 0.0,
+            other_responses: Vec::new(),
+            from_cache: false,
+        }));
+    }
+}
+
+async fn wait_for_responses(receivers: Vec<ResponseRx>) {
+    for mut receiver in receivers {
+        receiver.recv().await.unwrap().unwrap();
+    }
+}
+
+/// Queue that pushes ready batches to the batching task
+async fn run_current(requests: usize) {
+    let (queue, mut batches) = Queue::new(policy(), config(), MAX_CONCURRENT_BATCHES, None);
+    tokio::spawn(async move {
+        while let Some(((entries, _, _), permit)) = batches.recv().await {
+            tokio::spawn(async move {
+                respond(entries);
+                drop(permit);
+            });
+        }
+    });
+
+    let mut receivers = Vec::with_capacity(requests);
+    for i in 0..requests {
+        let (entry, receiver) = entry(i);
+        queue.append(entry).unwrap();
+        receivers.push(receiver);
+    }
+    wait_for_responses(receivers).await;
+}
+
+/// Queue whose batching task asks the actor for every batch
+async fn run_legacy(requests: usize) {
+    let queue = legacy::Queue::new(policy(), config());
+    let notify = Arc::new(tokio::sync::Notify::new());
+    let batching_task = tokio::spawn(legacy::batching_task(
+        queue.clone(),
+        notify.clone(),
+        MAX_CONCURRENT_BATCHES,
+    ));
+
+    let mut receivers = Vec::with_capacity(requests);
+    for i in 0..requests {
+        let (entry, receiver) = entry(i);
+        queue.append(entry);
+        notify.notify_one();
+        receivers.push(receiver);
+    }
+    wait_for_responses(receivers).await;
+    // It never stops on its own, and the actor stops with it
+    batching_task.abort();
+}
+
+/// The queue as it was before batches were pushed: the batching task is woken up for
+/// every request and fetches each batch through a oneshot, and every cut sweeps the
+/// whole queue, reports the tenant depths, counts the lane lengths and walks the
+/// round-robin over cloned tenant turns.
+///
+/// The queue state is ported from that version without length buckets, priority aging
+/// and dedup, which the benchmark does not use.
+mod legacy {
+    use super::respond;
+    use reply_client::{ClientBatch, HttpRequest};
+    use router::policy::{BatchPolicy, BatchUsage};
+    use router::processor::ProcessorError;
+    use router::queue::{NextBatch, QueueConfig, QueueEntry, TenantWeights};
+    use router::Priority;
+    use std::collections::{HashMap, HashSet, VecDeque};
+    use std::sync::Arc;
+    use tokio::sync::{mpsc, oneshot, Notify, Semaphore};
+    use tokio::time::Instant;
+    use tracing::{instrument, Span};
+
+    enum QueueCommand {
+        Append(Box<QueueEntry>),
+        NextBatch(oneshot::Sender<BatchStatus>),
+    }
+
+    enum BatchStatus {
+        Ready(NextBatch),
+        Pending(Instant),
+        Empty,
+    }
+
+    #[derive(Clone)]
+    pub struct Queue {
+        queue_sender: mpsc::UnboundedSender<QueueCommand>,
+    }
+
+    impl Queue {
+        pub fn new(policy: Arc<dyn BatchPolicy>, config: QueueConfig) -> Self {
+            let (queue_sender, mut queue_receiver) = mpsc::unbounded_channel();
+            let mut state = QueueState::new(policy, config);
+            tokio::spawn(async move {
+                while let Some(command) = queue_receiver.recv().await {
+                    match command {
+                        QueueCommand::Append(entry) => state.append(*entry),
+                        QueueCommand::NextBatch(response_sender) => {
+                            let _ = response_sender.send(state.next_batch());
+                        }
+                    }
+                }
+            });
+            Self { queue_sender }
+        }
+
+        pub fn append(&self, entry: QueueEntry) {
+            let command = QueueCommand::Append(Box::new(entry));
+            self.queue_sender.send(command).unwrap();
+        }
+
+        async fn next_batch(&self) -> BatchStatus {
+            let (response_sender, response_receiver) = oneshot::channel();
+            let command = QueueCommand::NextBatch(response_sender);
+            self.queue_sender.send(command).unwrap();
+            response_receiver.await.unwrap()
+        }
+    }
+
+    pub async fn batching_task(queue: Queue, notify: Arc<Notify>, max_concurrent_batches: usize) {
+        let in_flight = Arc::new(Semaphore::new(max_concurrent_batches));
+        loop {
+            notify.notified().await;
+            loop {
+                let permit = in_flight.clone().acquire_owned().await.unwrap();
+                let (entries, _, _) = match queue.next_batch().await {
+                    BatchStatus::Ready(next_batch) => next_batch,
+                    BatchStatus::Pending(deadline) => {
+                        tokio::select! {
+                            _ = tokio::time::sleep_until(deadline) => {}
+                            _ = notify.notified() => {}
+                        }
+                        continue;
+                    }
+                    BatchStatus::Empty => break,
+                };
+                tokio::spawn(async move {
+                    respond(entries);
+                    drop(permit);
+                });
+            }
+        }
+    }
+
+    fn is_abandoned(entry: &QueueEntry) -> bool {
+        entry.response_tx.is_closed() && entry.followers.iter().all(is_abandoned)
+    }
+
+    fn weight(weights: &TenantWeights, tenant: &str) -> usize {
+        weights.0.get(tenant).copied().unwrap_or(1).max(1)
+    }
+
+    #[derive(Default)]
+    struct Lane {
+        tenants: HashMap<Arc<str>, VecDeque<(u64, QueueEntry)>>,
+        turns: VecDeque<Arc<str>>,
+        quota: usize,
+    }
+
+    impl Lane {
+        fn len(&self) -> usize {
+            self.tenants.values().map(VecDeque::len).sum()
+        }
+
+        fn oldest(&self) -> Option<Instant> {
+            self.tenants
+                .values()
+                .filter_map(|entries| entries.front())
+                .map(|(_, entry)| entry.queue_time)
+                .min()
+        }
+
+        fn push_back(&mut self, entry: (u64, QueueEntry)) {
+            let tenant = entry.1.tenant();
+            if !self.tenants.contains_key(tenant) {
+                let tenant: Arc<str> = Arc::from(tenant);
+                self.turns.push_back(tenant.clone());
+                self.tenants.insert(tenant, VecDeque::new());
+            }
+            self.tenants.get_mut(tenant).unwrap().push_back(entry);
+        }
+
+        fn retain(&mut self, mut f: impl FnMut(&(u64, QueueEntry)) -> bool) {
+            for entries in self.tenants.values_mut() {
+                entries.retain(&mut f);
+            }
+            self.forget_idle();
+        }
+
+        fn forget_idle(&mut self) {
+            self.tenants.retain(|_, entries| !entries.is_empty());
+            let front = self.turns.front().cloned();
+            self.turns
+                .retain(|tenant| self.tenants.contains_key(tenant));
+            if self.turns.front() != front.as_ref() {
+                self.quota = 0;
+            }
+        }
+
+        fn schedule<'a>(&'a self, weights: &'a TenantWeights) -> Schedule<'a> {
+            Schedule {
+                lane: self,
+                weights,
+                turns: self.turns.clone(),
+                quota: self.quota,
+                taken: HashMap::new(),
+            }
+        }
+
+        fn take(&mut self, n: usize, weights: &TenantWeights) -> Vec<(u64, QueueEntry)> {
+            let mut schedule = self.schedule(weights);
+            for _ in 0..n {
+                schedule.next();
+            }
+            let Schedule {
+                turns,
+                quota,
+                taken,
+                ..
+            } = schedule;
+            self.turns = turns;
+            self.quota = quota;
+
+            let mut entries = Vec::with_capacity(n);
+            for (tenant, count) in taken {
+                entries.extend(self.tenants.get_mut(&tenant).unwrap().drain(..count));
+            }
+            self.forget_idle();
+            entries
+        }
+    }
+
+    struct Schedule<'a> {
+        lane: &'a Lane,
+        weights: &'a TenantWeights,
+        turns: VecDeque<Arc<str>>,
+        quota: usize,
+        taken: HashMap<Arc<str>, usize>,
+    }
+
+    impl<'a> Iterator for Schedule<'a> {
+        type Item = &'a (u64, QueueEntry);
+
+        fn next(&mut self) -> Option<Self::Item> {
+            loop {
+                let (tenant, entries) = self.lane.tenants.get_key_value(self.turns.front()?)?;
+                let taken = self.taken.entry(tenant.clone()).or_default();
+                let Some(entry) = entries.get(*taken) else {
+                    self.turns.pop_front();
+                    self.quota = 0;
+                    continue;
+                };
+                if self.quota == 0 {
+                    self.quota = weight(self.weights, tenant);
+                }
+                *taken += 1;
+                self.quota -= 1;
+                if self.quota == 0 {
+                    self.turns.rotate_left(1);
+                }
+                return Some(entry);
+            }
+        }
+    }
+
+    struct QueueState {
+        /// One lane per priority class indexed by [`Priority::rank`]
+        lanes: Vec<Lane>,
+        next_id: u64,
+        next_batch_id: u64,
+        policy: Arc<dyn BatchPolicy>,
+        config: QueueConfig,
+        reported_tenants: HashSet<Arc<str>>,
+    }
+
+    impl QueueState {
+        fn new(policy: Arc<dyn BatchPolicy>, config: QueueConfig) -> Self {
+            Self {
+                lanes: (0..Priority::CLASSES).map(|_| Lane::default()).collect(),
+                next_id: 1,
+                next_batch_id: 1,
+                policy,
+                config,
+                reported_tenants: HashSet::new(),
+            }
+        }
+
+        fn report_tenant_depths(&mut self) {
+            let mut depths: HashMap<Arc<str>, usize> = HashMap::new();
+            for lane in &self.lanes {
+                for (tenant, entries) in &lane.tenants {
+                    *depths.entry(tenant.clone()).or_default() += entries.len();
+                }
+            }
+            for tenant in self.reported_tenants.drain() {
+                if !depths.contains_key(&tenant) {
+                    metrics::gauge!("router_tenant_queue_depth", "tenant" => tenant.to_string())
+                        .set(0.0);
+                }
+            }
+            for (tenant, depth) in depths {
+                metrics::gauge!("router_tenant_queue_depth", "tenant" => tenant.to_string())
+                    .set(depth as f64);
+                self.reported_tenants.insert(tenant);
+            }
+        }
+
+        fn append(&mut self, entry: QueueEntry) {
+            let id = self.next_id;
+            self.lanes[entry.request.priority.rank()].push_back((id, entry));
+            self.next_id += 1;
+        }
+
+        #[instrument(skip_all, fields(batch_size, next_batch_id))]
+        fn next_batch(&mut self) -> BatchStatus {
+            let now = Instant::now();
+            for lane in &mut self.lanes {
+                lane.retain(|(_, entry)| {
+                    if entry.deadline <= now {
+                        entry.respond(Err(ProcessorError::Timeout));
+                        return false;
+                    }
+                    !is_abandoned(entry)
+                });
+            }
+            self.report_tenant_depths();
+
+            let Some(oldest) = self.lanes.iter().filter_map(Lane::oldest).min() else {
+                return BatchStatus::Empty;
+            };
+            let weights = &self.config.tenant_weights;
+            let mut schedules = self
+                .lanes
+                .iter()
+                .map(|lane| lane.schedule(weights).peekable())
+                .collect::<Vec<_>>();
+            let mut taken = vec![0; self.lanes.len()];
+            let mut usage = BatchUsage::default();
+            let mut full = false;
+            while let Some(rank) = (0..self.lanes.len())
+                .filter_map(|rank| schedules[rank].peek().map(|(_, entry)| (rank, entry)))
+                .max_by_key(|(rank, entry)| (*rank, std::cmp::Reverse(entry.queue_time)))
+                .map(|(rank, _)| rank)
+            {
+                let (_, entry) = schedules[rank].next().unwrap();
+                let cost = self.policy.cost(&entry.request);
+                if usage.requests > 0 && !self.policy.fits(&usage, cost) {
+                    full = true;
+                    break;
+                }
+                usage.add(cost);
+                taken[rank] += 1;
+            }
+            drop(schedules);
+
+            let deadline = self.policy.deadline(oldest);
+            let starving = now - oldest >= self.config.bucketing.starvation;
+            if !(full || self.policy.is_full(&usage) || now >= deadline || starving) {
+                return BatchStatus::Pending(deadline);
+            }
+
+            let entries = self.lanes.iter().map(Lane::len).sum::<usize>();
+            let mut batch = Vec::with_capacity(usage.requests);
+            for (lane, &n) in self.lanes.iter_mut().zip(&taken).rev() {
+                batch.extend(lane.take(n, &self.config.tenant_weights));
+            }
+            let batch_size = batch.len();
+            tracing::info!(
+                "Gathering batch of {} from {} entries (0 coalesced)",
+                batch_size,
+                entries
+            );
+
+            let mut batch_entries = HashMap::with_capacity(batch_size);
+            let mut batch_requests = Vec::with_capacity(batch_size);
+            for (id, entry) in batch {
+                batch_requests.push(HttpRequest::new(id, entry.request.message.clone()));
+                batch_entries.insert(id, entry);
+            }
+
+            let span = Span::current();
+            span.record("batch_size", batch_size);
+            span.record("next_batch_id", self.next_batch_id);
+            let batch = ClientBatch::new(self.next_batch_id, batch_size as u32, batch_requests);
+            self.next_batch_id += 1;
+            BatchStatus::Ready((batch_entries, batch, span))
+        }
+    }
+}
+
+fn queue_throughput(c: &mut Criterion) {
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let mut group = c.benchmark_group("queue");
+    group.sample_size(10);
+    for requests in [10_000, 50_000, 100_000] {
+        group.throughput(Throughput::Elements(requests as u64));
+        group.bench_with_input(
+            BenchmarkId::new("current", requests),
+            &requests,
+            |b, &requests| b.to_async(&runtime).iter(|| run_current(requests)),
+        );
+        group.bench_with_input(
+            BenchmarkId::new("legacy", requests),
+            &requests,
+            |b, &requests| b.to_async(&runtime).iter(|| run_legacy(requests)),
+        );
+    }
+    group.finish();
+}
+
+criterion_group!(benches, queue_throughput);
+criterion_main!(benches);
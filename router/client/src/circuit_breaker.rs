@@ -0,0 +1,155 @@
+use std::fmt::Display;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone)]
+pub struct CircuitBreakerConfig {
+    /// Failed calls in a row that open the circuit
+    pub failure_threshold: u32,
+    /// Time the circuit stays open before trial calls are let through
+    pub cool_down: Duration,
+    /// Number of trial calls allowed at the same time while half-open
+    pub half_open_max_calls: u32,
+}
+
+impl Default for CircuitBreakerConfig {
+    fn default() -> Self {
+        Self {
+            failure_threshold: 5,
+            cool_down: Duration::from_secs(10),
+            half_open_max_calls: 1,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CircuitState {
+    /// Calls go through
+    Closed,
+    /// Calls fail fast until the cool-down has passed
+    Open,
+    /// A few trial calls decide whether the circuit closes again
+    HalfOpen,
+}
+
+impl Display for CircuitState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CircuitState::Closed => write!(f, "closed"),
+            CircuitState::Open => write!(f, "open"),
+            CircuitState::HalfOpen => write!(f, "half_open"),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Inner {
+    state: CircuitState,
+    consecutive_failures: u32,
+    opened_at: Instant,
+    /// Trial calls in flight while half-open
+    trial_calls: u32,
+}
+
+#[derive(Debug)]
+pub(crate) struct CircuitBreaker {
+    config: CircuitBreakerConfig,
+    inner: Mutex<Inner>,
+}
+
+impl CircuitBreaker {
+    pub fn new(config: CircuitBreakerConfig) -> Self {
+        Self {
+            config,
+            inner: Mutex::new(Inner {
+                state: CircuitState::Closed,
+                consecutive_failures: 0,
+                opened_at: Instant::now(),
+                trial_calls: 0,
+            }),
+        }
+    }
+
+    pub fn state(&self) -> CircuitState {
+        let mut inner = self.inner.lock().unwrap();
+        self.refresh(&mut inner);
+        inner.state
+    }
+
+    /// Permit to make a call, or `None` if the call has to fail fast
+    pub fn try_acquire(self: &Arc<Self>) -> Option<CallPermit> {
+        let mut inner = self.inner.lock().unwrap();
+        self.refresh(&mut inner);
+        let trial = match inner.state {
+            CircuitState::Closed => false,
+            CircuitState::Open => return None,
+            CircuitState::HalfOpen => {
+                if inner.trial_calls >= self.config.half_open_max_calls {
+                    return None;
+                }
+                inner.trial_calls += 1;
+                true
+            }
+        };
+        Some(CallPermit {
+            breaker: self.clone(),
+            trial,
+        })
+    }
+
+    fn on_success(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.state = CircuitState::Closed;
+        inner.consecutive_failures = 0;
+        inner.trial_calls = 0;
+    }
+
+    fn on_failure(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.consecutive_failures += 1;
+        let trip = match inner.state {
+            CircuitState::Closed => inner.consecutive_failures >= self.config.failure_threshold,
+            CircuitState::HalfOpen => true,
+            CircuitState::Open => false,
+        };
+        if trip {
+            inner.state = CircuitState::Open;
+            inner.opened_at = Instant::now();
+            inner.trial_calls = 0;
+        }
+    }
+
+    /// Move an open circuit to half-open once the cool-down has passed
+    fn refresh(&self, inner: &mut Inner) {
+        if inner.state == CircuitState::Open && inner.opened_at.elapsed() >= self.config.cool_down {
+            inner.state = CircuitState::HalfOpen;
+            inner.trial_calls = 0;
+        }
+    }
+}
+
+/// A call let through by the circuit breaker. A trial call that is dropped without
+/// an outcome, e.g. because it was cancelled, frees its slot.
+pub(crate) struct CallPermit {
+    breaker: Arc<CircuitBreaker>,
+    trial: bool,
+}
+
+impl CallPermit {
+    pub fn on_success(self) {
+        self.breaker.on_success();
+    }
+
+    pub fn on_failure(self) {
+        self.breaker.on_failure();
+    }
+}
+
+impl Drop for CallPermit {
+    fn drop(&mut self) {
+        if self.trial {
+            let mut inner = self.breaker.inner.lock().unwrap();
+            inner.trial_calls = inner.trial_calls.saturating_sub(1);
+        }
+    }
+}
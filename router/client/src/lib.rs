@@ -1,12 +1,78 @@
+mod circuit_breaker;
 mod pb {
     include!("pb/mod.rs");
 }
 
+use circuit_breaker::CircuitBreaker;
+pub use circuit_breaker::{CircuitBreakerConfig, CircuitState};
+
 use pb::reply::v1::reply_service_client::ReplyServiceClient;
-use pb::reply::v1::{Batch, ReplyRequest, ReplyResponse};
+use pb::reply::v1::{Batch, ReplyRequest};
+pub use pb::reply::v1::{ReplyResponse, RequestError, Response};
 
 use anyhow::Result;
+use std::sync::Arc;
+use std::time::Duration;
+use thiserror::Error;
 use tonic::transport::{Channel, Uri};
+use tonic::{Code, Status};
+use tonic_health::pb::health_check_response::ServingStatus;
+use tonic_health::pb::health_client::HealthClient;
+use tonic_health::pb::HealthCheckRequest;
+
+/// Name of the reply service in the `grpc.health.v1` protocol
+const SERVICE_NAME: &str = "reply.v1.ReplyService";
+
+#[derive(Debug, Error)]
+pub enum ClientError {
+    #[error("gRPC call failed with {:?}: {}", .0.code(), .0.message())]
+    Status(#[from] Status),
+    #[error("Circuit breaker is open")]
+    CircuitOpen,
+}
+
+impl ClientError {
+    /// Whether sending the same batch again may succeed
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            ClientError::Status(status) => matches!(
+                status.code(),
+                Code::Unavailable | Code::Aborted | Code::ResourceExhausted | Code::Unknown
+            ),
+            // Another backend may still take the batch
+            ClientError::CircuitOpen => true,
+        }
+    }
+
+    /// Whether the call ran out of time before the server answered
+    pub fn is_timeout(&self) -> bool {
+        match self {
+            ClientError::Status(status) => status.code() == Code::DeadlineExceeded,
+            ClientError::CircuitOpen => false,
+        }
+    }
+
+    /// Whether the batch was rejected for its size, so that smaller batches may go through
+    pub fn is_too_large(&self) -> bool {
+        match self {
+            // Raised by tonic when a message exceeds the gRPC max message size
+            ClientError::Status(status) => {
+                status.code() == Code::OutOfRange
+                    && status.message().contains("message length too large")
+            }
+            ClientError::CircuitOpen => false,
+        }
+    }
+
+    /// Whether the server ran out of a resource. That is how servers answer both when
+    /// they are overloaded and when a batch is too large for them.
+    pub fn is_resource_exhausted(&self) -> bool {
+        match self {
+            ClientError::Status(status) => status.code() == Code::ResourceExhausted,
+            ClientError::CircuitOpen => false,
+        }
+    }
+}
 
 #[derive(Debug)]
 
@@ -24,12 +90,50 @@ impl HttpRequest {
 pub struct ClientBatch {
     pub id: u64,
     pub size: u32,
+    /// Sent to the server as the gRPC deadline of the call
+    pub timeout: Option<Duration>,
     requests: Vec<HttpRequest>,
 }
 
 impl ClientBatch {
     pub fn new(id: u64, size: u32, requests: Vec<HttpRequest>) -> Self {
-        Self { id, size, requests }
+        Self {
+            id,
+            size,
+            timeout: None,
+            requests,
+        }
+    }
+
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.requests.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.requests.is_empty()
+    }
+
+    pub fn request_ids(&self) -> impl Iterator<Item = u64> + '_ {
+        self.requests.iter().map(|request| request.id)
+    }
+
+    /// Split the batch into two halves with the same id and timeout. The first half
+    /// gets the extra request of an odd-sized batch.
+    pub fn split(mut self) -> (Self, Self) {
+        let second = self.requests.split_off(self.requests.len().div_ceil(2));
+        self.size = self.requests.len() as u32;
+        let second = Self {
+            id: self.id,
+            size: second.len() as u32,
+            timeout: self.timeout,
+            requests: second,
+        };
+        (self, second)
     }
 
     fn to_grpc_batch(&self) -> Batch {
@@ -48,23 +152,88 @@ impl ClientBatch {
     }
 }
 
-#[derive(Clone)]
+#[derive(Debug, Clone)]
 pub struct Client {
     stub: ReplyServiceClient<Channel>,
+    health: HealthClient<Channel>,
+    /// Shared by all clones of the client
+    breaker: Arc<CircuitBreaker>,
 }
 
 impl Client {
-    pub async fn new(uri: Uri) -> Result<Self> {
-        let channel = Channel::builder(uri).connect().await?;
+    /// Create a client for the server at `uri`. It connects on the first call and
+    /// reconnects whenever the connection is lost, so an unreachable server only fails
+    /// the calls made to it.
+    pub fn new(uri: Uri) -> Self {
+        let channel = Channel::builder(uri).connect_lazy();
 
-        Ok(Self {
-            stub: ReplyServiceClient::new(channel),
-        })
+        Self {
+            stub: ReplyServiceClient::new(channel.clone()),
+            health: HealthClient::new(channel),
+            breaker: Arc::new(CircuitBreaker::new(CircuitBreakerConfig::default())),
+        }
     }
 
-    pub async fn generate_reply(&mut self, request: ClientBatch) -> Result<ReplyResponse> {
+    pub fn with_circuit_breaker(mut self, config: CircuitBreakerConfig) -> Self {
+        self.breaker = Arc::new(CircuitBreaker::new(config));
+        self
+    }
+
+    pub fn circuit_state(&self) -> CircuitState {
+        self.breaker.state()
+    }
+
+    /// Ask the server through `grpc.health.v1` whether the reply service is serving
+    pub async fn health_check(&mut self) -> Result<bool, ClientError> {
+        let response = self
+            .health
+            .check(HealthCheckRequest {
+                service: SERVICE_NAME.to_string(),
+            })
+            .await?;
+        Ok(response.into_inner().status() == ServingStatus::Serving)
+    }
+
+    pub async fn generate_reply(
+        &mut self,
+        request: &ClientBatch,
+    ) -> Result<ReplyResponse, ClientError> {
+        self.generate_reply_with(request, ClientError::is_too_large)
+            .await
+    }
+
+    /// Like [`Client::generate_reply`], but the circuit breaker does not count errors
+    /// for which `rejects_request` holds as failures of the server. The caller may know
+    /// better than the status code whether the server rejected the request itself.
+    pub async fn generate_reply_with(
+        &mut self,
+        request: &ClientBatch,
+        rejects_request: impl FnOnce(&ClientError) -> bool,
+    ) -> Result<ReplyResponse, ClientError> {
+        let Some(permit) = self.breaker.try_acquire() else {
+            return Err(ClientError::CircuitOpen);
+        };
+
         let batch = request.to_grpc_batch();
-        let response = self.stub.reply(ReplyRequest { batch: Some(batch) }).await?;
-        Ok(response.into_inner())
+        let mut grpc_request = tonic::Request::new(ReplyRequest { batch: Some(batch) });
+        if let Some(timeout) = request.timeout {
+            grpc_request.set_timeout(timeout);
+        }
+        match self.stub.reply(grpc_request).await {
+            Ok(response) => {
+                permit.on_success();
+                Ok(response.into_inner())
+            }
+            Err(status) => {
+                let error = ClientError::Status(status);
+                // Errors about the request itself say nothing about the server's health
+                if (error.is_retryable() || error.is_timeout()) && !rejects_request(&error) {
+                    permit.on_failure();
+                } else {
+                    permit.on_success();
+                }
+                Err(error)
+            }
+        }
     }
 }
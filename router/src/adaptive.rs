@@ -0,0 +1,205 @@
+use crate::policy::{BatchPolicy, BatchUsage};
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// Number of recent latencies kept per batch size bucket
+const LATENCY_SAMPLES: usize = 64;
+/// Weight of the newest gap in the moving average of the time between two arrivals
+const ARRIVAL_SMOOTHING: f64 = 0.1;
+
+/// Picks the wait window and the target batch size from the observed arrival rate and
+/// backend latency, so that the p99 latency of a request stays below a target.
+///
+/// A request waits at most for the batch to fill up and then for the backend to answer
+/// it, so the policy picks the largest batch size for which the expected time to gather
+/// the batch plus the p99 backend latency at that size stays within the target. If even
+/// a single request cannot meet the target, batches are sent without waiting.
+#[derive(Debug)]
+pub struct Adaptive {
+    max_batch_size: usize,
+    max_wait: Duration,
+    latency_target: Duration,
+    state: Mutex<State>,
+}
+
+#[derive(Debug)]
+struct State {
+    last_arrival: Option<Instant>,
+    /// Moving average of the time between two arrivals in seconds
+    arrival_gap: Option<f64>,
+    /// Recent backend latencies, bucketed by batch size rounded up to a power of two
+    latencies: Vec<VecDeque<Duration>>,
+    decision: Decision,
+}
+
+/// Batching parameters currently in use
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Decision {
+    window: Duration,
+    target_batch_size: usize,
+}
+
+impl Adaptive {
+    pub fn new(max_batch_size: usize, max_wait: Duration, latency_target: Duration) -> Self {
+        let buckets = bucket(max_batch_size) + 1;
+        Self {
+            max_batch_size,
+            max_wait,
+            latency_target,
+            state: Mutex::new(State {
+                last_arrival: None,
+                arrival_gap: None,
+                latencies: vec![VecDeque::with_capacity(LATENCY_SAMPLES); buckets],
+                // Behave like size-or-timeout until there is something to go by
+                decision: Decision {
+                    window: max_wait,
+                    target_batch_size: max_batch_size,
+                },
+            }),
+        }
+    }
+
+    fn decide(&self, state: &mut State, now: Instant) {
+        let rate = state.arrival_rate(now);
+        let target = self.latency_target.as_secs_f64();
+        let p99 = state.p99_latencies();
+
+        let mut chosen = None;
+        for size in 1..=self.max_batch_size {
+            // Time until `size` requests have arrived after the first one
+            let fill = if size == 1 {
+                0.0
+            } else if rate > 0.0 {
+                (size - 1) as f64 / rate
+            } else {
+                f64::INFINITY
+            };
+            if fill > self.max_wait.as_secs_f64() {
+                break;
+            }
+            let latency = closest(&p99, size).as_secs_f64();
+            if fill + latency <= target {
+                chosen = Some((size, fill, latency));
+            }
+        }
+
+        let (decision, expected) = match chosen {
+            Some((size, fill, latency)) => (
+                Decision {
+                    window: Duration::from_secs_f64(fill),
+                    target_batch_size: size,
+                },
+                fill + latency,
+            ),
+            // The target cannot be met, at least do not make it worse by waiting
+            None => (
+                Decision {
+                    window: Duration::ZERO,
+                    target_batch_size: self.max_batch_size,
+                },
+                closest(&p99, self.max_batch_size).as_secs_f64(),
+            ),
+        };
+        let changed = decision != state.decision;
+        state.decision = decision;
+
+        if changed {
+            tracing::info!(
+                arrival_rate = rate,
+                window_ms = decision.window.as_secs_f64() * 1000.0,
+                target_batch_size = decision.target_batch_size,
+                expected_p99_ms = expected * 1000.0,
+                target_met = chosen.is_some(),
+                "Adapted batching window"
+            );
+        }
+        metrics::gauge!("router_adaptive_arrival_rate").set(rate);
+        metrics::gauge!("router_adaptive_window_seconds").set(decision.window.as_secs_f64());
+        metrics::gauge!("router_adaptive_target_batch_size").set(decision.target_batch_size as f64);
+        metrics::gauge!("router_adaptive_expected_p99_seconds").set(expected);
+    }
+}
+
+impl State {
+    /// Requests per second, decaying while no requests arrive
+    fn arrival_rate(&self, now: Instant) -> f64 {
+        match (self.last_arrival, self.arrival_gap) {
+            (Some(last), Some(gap)) => {
+                let gap = gap.max((now - last).as_secs_f64());
+                if gap > 0.0 {
+                    1.0 / gap
+                } else {
+                    f64::INFINITY
+                }
+            }
+            _ => 0.0,
+        }
+    }
+
+    /// p99 of the recent backend latencies of every bucket
+    fn p99_latencies(&self) -> Vec<Option<Duration>> {
+        self.latencies
+            .iter()
+            .map(|samples| {
+                let mut sorted = samples.iter().copied().collect::<Vec<_>>();
+                sorted.sort_unstable();
+                let index = (sorted.len() * 99).div_ceil(100).checked_sub(1)?;
+                Some(sorted[index])
+            })
+            .collect()
+    }
+}
+
+/// Latency of the bucket of `size`. Sizes not seen yet borrow from the closest bucket
+/// that has been, and are assumed to be free if no batch has been answered so far.
+fn closest(p99: &[Option<Duration>], size: usize) -> Duration {
+    let wanted = bucket(size);
+    (0..p99.len())
+        .filter_map(|i| p99[i].map(|latency| (i, latency)))
+        .min_by_key(|(i, _)| i.abs_diff(wanted))
+        .map(|(_, latency)| latency)
+        .unwrap_or_default()
+}
+
+/// Bucket of a batch size: sizes are rounded up to the next power of two
+fn bucket(size: usize) -> usize {
+    size.max(1).next_power_of_two().trailing_zeros() as usize
+}
+
+impl BatchPolicy for Adaptive {
+    fn fits(&self, usage: &BatchUsage, _cost: usize) -> bool {
+        usage.requests < self.state.lock().unwrap().decision.target_batch_size
+    }
+
+    fn is_full(&self, usage: &BatchUsage) -> bool {
+        usage.requests >= self.state.lock().unwrap().decision.target_batch_size
+    }
+
+    fn deadline(&self, oldest: Instant) -> Instant {
+        oldest + self.state.lock().unwrap().decision.window
+    }
+
+    fn record_arrival(&self, now: Instant) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(last) = state.last_arrival {
+            let gap = (now - last).as_secs_f64();
+            state.arrival_gap = Some(match state.arrival_gap {
+                Some(average) => average * (1.0 - ARRIVAL_SMOOTHING) + gap * ARRIVAL_SMOOTHING,
+                None => gap,
+            });
+        }
+        state.last_arrival = Some(now);
+    }
+
+    fn record_batch(&self, size: usize, latency: Duration) {
+        let mut state = self.state.lock().unwrap();
+        let samples = &mut state.latencies[bucket(size.min(self.max_batch_size))];
+        if samples.len() == LATENCY_SAMPLES {
+            samples.pop_front();
+        }
+        samples.push_back(latency);
+        self.decide(&mut state, Instant::now());
+    }
+}
@@ -0,0 +1,279 @@
+use crate::health::{health_check_task, Health, OutlierConfig};
+use crate::BackendStatus;
+use reply_client::{CircuitState, Client, ClientError};
+use std::ops::Deref;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// How the balancer picks the backend for the next batch
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LoadBalancing {
+    /// Cycle through the backends in order
+    RoundRobin,
+    /// Backend with the fewest batches in flight
+    LeastOutstanding,
+    /// Fewer batches in flight out of two randomly chosen backends
+    PowerOfTwo,
+}
+
+/// Time after which a learned batch size cap is lifted, so that a backend that was
+/// reconfigured or recovered gets large batches again. The next batch above the cap
+/// probes whether it still rejects them.
+const BATCH_SIZE_CAP_TTL: Duration = Duration::from_secs(60);
+
+/// A gRPC server the router sends batches to
+#[derive(Debug)]
+pub struct Backend {
+    pub address: String,
+    pub client: Client,
+    /// Number of batches sent to this backend and not answered yet
+    in_flight: AtomicUsize,
+    pub(crate) health: Mutex<Health>,
+    /// Largest batch size the backend answered
+    accepted_batch_size: AtomicUsize,
+    /// Largest batch size the backend is known to accept, learned from batches it
+    /// rejected as too large, and when it was learned
+    batch_size_cap: Mutex<Option<(usize, Instant)>>,
+}
+
+impl Backend {
+    pub fn new(address: String, client: Client) -> Self {
+        Self {
+            address,
+            client,
+            in_flight: AtomicUsize::new(0),
+            health: Mutex::new(Health::new()),
+            accepted_batch_size: AtomicUsize::new(0),
+            batch_size_cap: Mutex::new(None),
+        }
+    }
+
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.load(Ordering::SeqCst)
+    }
+
+    /// Largest batch to send to this backend, if one was learned in the last
+    /// [`BATCH_SIZE_CAP_TTL`]
+    pub fn batch_size_cap(&self) -> Option<usize> {
+        let mut cap = self.batch_size_cap.lock().unwrap();
+        let (size, learned_at) = (*cap)?;
+        if learned_at.elapsed() < BATCH_SIZE_CAP_TTL {
+            return Some(size);
+        }
+        *cap = None;
+        tracing::info!("Lifting the batch size cap of backend {}", self.address);
+        metrics::gauge!("router_backend_batch_size_cap", "backend" => self.address.clone())
+            .set(0.0);
+        None
+    }
+
+    /// Record that the backend answered a batch of `size` requests
+    pub fn record_accepted(&self, size: usize) {
+        self.accepted_batch_size.fetch_max(size, Ordering::Relaxed);
+    }
+
+    /// Whether the backend rejected a batch of `size` requests for its size. A
+    /// resource exhausted error only counts for more than one request, and only if the
+    /// backend never answered a batch this large, as it otherwise means that the
+    /// backend is overloaded.
+    pub fn rejected_as_too_large(&self, error: &ClientError, size: usize) -> bool {
+        error.is_too_large()
+            || (error.is_resource_exhausted()
+                && size > self.accepted_batch_size.load(Ordering::Relaxed).max(1))
+    }
+
+    /// Record that the backend rejected a batch of `size` requests as too large.
+    /// Later batches are capped at half of it.
+    pub fn learn_batch_size_cap(&self, size: usize) {
+        let size = size.div_ceil(2).max(1);
+        let mut cap = self.batch_size_cap.lock().unwrap();
+        let previous = cap.map_or(usize::MAX, |(previous, _)| previous);
+        *cap = Some((size.min(previous), Instant::now()));
+        if size < previous {
+            tracing::warn!(
+                "Capping batches for backend {} at {} requests",
+                self.address,
+                size
+            );
+            metrics::gauge!("router_backend_batch_size_cap", "backend" => self.address.clone())
+                .set(size as f64);
+        }
+    }
+}
+
+/// Spreads batches over several backends, avoiding the ones that fail
+#[derive(Debug)]
+pub struct Balancer {
+    backends: Vec<Arc<Backend>>,
+    strategy: LoadBalancing,
+    outlier: OutlierConfig,
+    next: AtomicUsize,
+}
+
+impl Balancer {
+    pub fn new(backends: Vec<Backend>, strategy: LoadBalancing, outlier: OutlierConfig) -> Self {
+        assert!(!backends.is_empty(), "at least one backend is required");
+        Self {
+            backends: backends.into_iter().map(Arc::new).collect(),
+            strategy,
+            outlier,
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn backend_count(&self) -> usize {
+        self.backends.len()
+    }
+
+    /// Start probing every backend through `grpc.health.v1`
+    pub fn spawn_health_checks(&self, interval: Duration) {
+        for backend in &self.backends {
+            tokio::spawn(health_check_task(backend.clone(), interval));
+        }
+    }
+
+    pub fn status(&self) -> Vec<BackendStatus> {
+        let now = Instant::now();
+        self.backends
+            .iter()
+            .map(|backend| {
+                let health = backend.health.lock().unwrap();
+                BackendStatus {
+                    address: backend.address.clone(),
+                    state: health.state(now, &self.outlier),
+                    in_flight: backend.in_flight(),
+                    consecutive_failures: health.consecutive_failures(),
+                    ejections: health.ejections(),
+                    latency_ms: health.latency().map(|l| l.as_secs_f64() * 1000.0),
+                    circuit: backend.client.circuit_state().to_string(),
+                    batch_size_cap: backend.batch_size_cap(),
+                }
+            })
+            .collect()
+    }
+
+    /// Whether at least one backend accepts calls, i.e. has a circuit that is not open
+    pub fn any_available(&self) -> bool {
+        self.backends
+            .iter()
+            .any(|backend| backend.client.circuit_state() != CircuitState::Open)
+    }
+
+    /// Feed a batch answered after `latency` into outlier detection
+    pub fn record_success(&self, backend: &Backend, latency: Duration) {
+        let ejection = backend
+            .health
+            .lock()
+            .unwrap()
+            .record_success(latency, &self.outlier);
+        Self::log_ejection(backend, ejection);
+    }
+
+    /// Feed a failed batch into outlier detection
+    pub fn record_failure(&self, backend: &Backend) {
+        let ejection = backend.health.lock().unwrap().record_failure(&self.outlier);
+        Self::log_ejection(backend, ejection);
+    }
+
+    fn log_ejection(backend: &Backend, ejection: Option<Duration>) {
+        if let Some(ejection) = ejection {
+            tracing::warn!("Ejecting backend {} for {:?}", backend.address, ejection);
+            metrics::counter!("router_backend_ejections_total", "backend" => backend.address.clone())
+                .increment(1);
+        }
+    }
+
+    /// Pick the backend for the next batch. It counts as busy until the guard is dropped.
+    pub fn pick(&self) -> BackendGuard {
+        let now = Instant::now();
+        // Ejected backends and open circuits get no traffic, recovering backends a
+        // growing share of it
+        let mut candidates = (0..self.backends.len())
+            .filter(|&i| {
+                if self.backends[i].client.circuit_state() == CircuitState::Open {
+                    return false;
+                }
+                let weight = self.backends[i]
+                    .health
+                    .lock()
+                    .unwrap()
+                    .weight(now, &self.outlier);
+                weight >= 1.0 || rand::random::<f64>() < weight
+            })
+            .collect::<Vec<_>>();
+        if candidates.is_empty() {
+            // A struggling backend is still better than none
+            candidates = (0..self.backends.len()).collect();
+        }
+
+        let n = candidates.len();
+        let index = match self.strategy {
+            LoadBalancing::RoundRobin => candidates[self.next.fetch_add(1, Ordering::Relaxed) % n],
+            LoadBalancing::LeastOutstanding => {
+                // Rotate the starting point so that ties do not always go to the first backend
+                let start = self.next.fetch_add(1, Ordering::Relaxed);
+                (0..n)
+                    .map(|i| candidates[(start + i) % n])
+                    .min_by_key(|&i| self.backends[i].in_flight())
+                    .unwrap()
+            }
+            LoadBalancing::PowerOfTwo => {
+                if n == 1 {
+                    candidates[0]
+                } else {
+                    let first = rand::random::<usize>() % n;
+                    let second = (first + 1 + rand::random::<usize>() % (n - 1)) % n;
+                    let (first, second) = (candidates[first], candidates[second]);
+                    if self.backends[second].in_flight() < self.backends[first].in_flight() {
+                        second
+                    } else {
+                        first
+                    }
+                }
+            }
+        };
+
+        let backend = self.backends[index].clone();
+        backend.in_flight.fetch_add(1, Ordering::SeqCst);
+        BackendGuard { backend }
+    }
+
+    /// Pick a second backend for a batch already in flight on `primary`: the one with
+    /// the fewest batches in flight among the others that take traffic
+    pub fn pick_hedge(&self, primary: &Backend) -> Option<BackendGuard> {
+        let now = Instant::now();
+        let backend = self
+            .backends
+            .iter()
+            .filter(|backend| !std::ptr::eq(backend.as_ref(), primary))
+            .filter(|backend| {
+                backend.client.circuit_state() != CircuitState::Open
+                    && backend.health.lock().unwrap().weight(now, &self.outlier) > 0.0
+            })
+            .min_by_key(|backend| backend.in_flight())?
+            .clone();
+        backend.in_flight.fetch_add(1, Ordering::SeqCst);
+        Some(BackendGuard { backend })
+    }
+}
+
+/// A batch in flight on a backend
+pub struct BackendGuard {
+    backend: Arc<Backend>,
+}
+
+impl Deref for BackendGuard {
+    type Target = Backend;
+
+    fn deref(&self) -> &Self::Target {
+        &self.backend
+    }
+}
+
+impl Drop for BackendGuard {
+    fn drop(&mut self) {
+        self.backend.in_flight.fetch_sub(1, Ordering::SeqCst);
+    }
+}
@@ -0,0 +1,112 @@
+use crate::{TextReplyRequest, TextReplyResponse};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// Response cache parameters
+#[derive(Debug, Clone)]
+pub struct CacheConfig {
+    /// Maximum number of cached replies. Zero disables the cache.
+    pub capacity: usize,
+    /// Time a reply stays valid after it was cached
+    pub ttl: Duration,
+}
+
+/// In-memory LRU cache of successful replies, valid for a limited time
+#[derive(Debug)]
+pub struct ResponseCache {
+    config: CacheConfig,
+    inner: Mutex<Inner>,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    entries: HashMap<TextReplyRequest, Slot>,
+    /// Keys by the tick of their last use, least recently used first
+    recency: BTreeMap<u64, TextReplyRequest>,
+    tick: u64,
+}
+
+#[derive(Debug)]
+struct Slot {
+    response: TextReplyResponse,
+    expires: Instant,
+    /// Tick of the last use, the key of this slot in `recency`
+    tick: u64,
+}
+
+impl ResponseCache {
+    pub fn new(config: CacheConfig) -> Self {
+        Self {
+            config,
+            inner: Mutex::new(Inner::default()),
+        }
+    }
+
+    /// Key of a request: the message and all parameters. The message is taken as is,
+    /// as the backend may answer messages that only differ in whitespace differently,
+    /// and tenants do not share replies.
+    pub fn key(request: &TextReplyRequest) -> TextReplyRequest {
+        request.clone()
+    }
+
+    pub fn get(&self, key: &TextReplyRequest) -> Option<TextReplyResponse> {
+        if self.config.capacity == 0 {
+            return None;
+        }
+        let mut inner = self.inner.lock().unwrap();
+        let inner = &mut *inner;
+        let Some(slot) = inner.entries.get_mut(key) else {
+            metrics::counter!("router_cache_misses_total").increment(1);
+            return None;
+        };
+        inner.recency.remove(&slot.tick);
+        if slot.expires <= Instant::now() {
+            inner.entries.remove(key);
+            metrics::gauge!("router_cache_entries").set(inner.entries.len() as f64);
+            metrics::counter!("router_cache_misses_total").increment(1);
+            return None;
+        }
+
+        metrics::counter!("router_cache_hits_total").increment(1);
+        inner.tick += 1;
+        slot.tick = inner.tick;
+        inner.recency.insert(slot.tick, key.clone());
+        Some(slot.response.clone())
+    }
+
+    pub fn insert(&self, key: TextReplyRequest, response: TextReplyResponse) {
+        if self.config.capacity == 0 {
+            return;
+        }
+        let mut inner = self.inner.lock().unwrap();
+        inner.tick += 1;
+        let tick = inner.tick;
+        let slot = Slot {
+            response,
+            expires: Instant::now() + self.config.ttl,
+            tick,
+        };
+        if let Some(previous) = inner.entries.insert(key.clone(), slot) {
+            inner.recency.remove(&previous.tick);
+        }
+        inner.recency.insert(tick, key);
+
+        while inner.entries.len() > self.config.capacity {
+            let (_, key) = inner.recency.pop_first().unwrap();
+            inner.entries.remove(&key);
+        }
+        metrics::gauge!("router_cache_entries").set(inner.entries.len() as f64);
+    }
+
+    /// Drop every cached reply, returning how many there were
+    pub fn flush(&self) -> usize {
+        let mut inner = self.inner.lock().unwrap();
+        let flushed = inner.entries.len();
+        inner.entries.clear();
+        inner.recency.clear();
+        metrics::gauge!("router_cache_entries").set(0.0);
+        flushed
+    }
+}
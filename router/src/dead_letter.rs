@@ -0,0 +1,146 @@
+use crate::queue::QueueEntry;
+use crate::TextReplyRequest;
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// A failed attempt to get a reply for a request
+#[derive(serde::Serialize, Debug, Clone)]
+pub struct FailedAttempt {
+    /// Milliseconds since the Unix epoch
+    pub at_ms: u64,
+    pub error: String,
+}
+
+impl FailedAttempt {
+    pub fn now(error: String) -> Self {
+        Self {
+            at_ms: unix_ms(SystemTime::now()),
+            error,
+        }
+    }
+}
+
+/// A request that failed for good, kept for inspection and replay
+#[derive(serde::Serialize, Debug, Clone)]
+pub struct DeadLetter {
+    pub id: u64,
+    pub request: TextReplyRequest,
+    pub tenant: String,
+    /// Number of times the request was sent to a backend
+    pub attempts: u32,
+    /// Errors of all attempts, oldest first
+    pub failures: Vec<FailedAttempt>,
+    /// Milliseconds since the Unix epoch
+    pub queued_at_ms: u64,
+    /// Milliseconds since the Unix epoch
+    pub dead_lettered_at_ms: u64,
+}
+
+/// Bounded store of dead letters. Once full, the oldest ones are dropped.
+#[derive(Debug)]
+pub struct DeadLetters {
+    /// Maximum number of dead letters kept. Zero disables the store.
+    capacity: usize,
+    inner: Mutex<Inner>,
+}
+
+#[derive(Debug)]
+struct Inner {
+    letters: VecDeque<DeadLetter>,
+    next_id: u64,
+}
+
+impl DeadLetters {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            inner: Mutex::new(Inner {
+                letters: VecDeque::new(),
+                next_id: 1,
+            }),
+        }
+    }
+
+    /// Keep the requests of an entry that failed for good, one dead letter for the
+    /// entry and one for every request coalesced into it. Returns their ids.
+    pub fn record(&self, entry: &QueueEntry) -> Vec<u64> {
+        if self.capacity == 0 {
+            return Vec::new();
+        }
+        let now = SystemTime::now();
+        let mut inner = self.inner.lock().unwrap();
+        let mut ids = Vec::with_capacity(1 + entry.followers.len());
+        // Followers were sent as part of the entry and share its attempts
+        for request in std::iter::once(entry).chain(&entry.followers) {
+            let id = inner.next_id;
+            inner.next_id += 1;
+            inner.letters.push_back(DeadLetter {
+                id,
+                request: request.request.clone(),
+                tenant: request.tenant().to_string(),
+                attempts: entry.attempts,
+                failures: entry.failures.clone(),
+                queued_at_ms: unix_ms(now - request.queue_time.elapsed()),
+                dead_lettered_at_ms: unix_ms(now),
+            });
+            if inner.letters.len() > self.capacity {
+                inner.letters.pop_front();
+                metrics::counter!("router_dead_letters_dropped_total").increment(1);
+            }
+            ids.push(id);
+        }
+        metrics::counter!("router_dead_lettered_total").increment(ids.len() as u64);
+        metrics::gauge!("router_dead_letters").set(inner.letters.len() as f64);
+        ids
+    }
+
+    /// All dead letters, oldest first
+    pub fn list(&self) -> Vec<DeadLetter> {
+        self.inner.lock().unwrap().letters.iter().cloned().collect()
+    }
+
+    pub fn get(&self, id: u64) -> Option<DeadLetter> {
+        let inner = self.inner.lock().unwrap();
+        inner.letters.iter().find(|letter| letter.id == id).cloned()
+    }
+
+    /// Remove and return a dead letter
+    pub fn take(&self, id: u64) -> Option<DeadLetter> {
+        let mut inner = self.inner.lock().unwrap();
+        let index = inner.letters.iter().position(|letter| letter.id == id)?;
+        let letter = inner.letters.remove(index);
+        metrics::gauge!("router_dead_letters").set(inner.letters.len() as f64);
+        letter
+    }
+
+    /// Return a dead letter that was taken, at its place among the others
+    pub fn put_back(&self, letter: DeadLetter) {
+        if self.capacity == 0 {
+            return;
+        }
+        let mut inner = self.inner.lock().unwrap();
+        let index = inner.letters.partition_point(|other| other.id < letter.id);
+        inner.letters.insert(index, letter);
+        if inner.letters.len() > self.capacity {
+            inner.letters.pop_front();
+            metrics::counter!("router_dead_letters_dropped_total").increment(1);
+        }
+        metrics::gauge!("router_dead_letters").set(inner.letters.len() as f64);
+    }
+
+    /// Drop every dead letter, returning how many there were
+    pub fn purge(&self) -> usize {
+        let mut inner = self.inner.lock().unwrap();
+        let purged = inner.letters.len();
+        inner.letters.clear();
+        metrics::gauge!("router_dead_letters").set(0.0);
+        purged
+    }
+}
+
+fn unix_ms(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
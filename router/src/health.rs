@@ -0,0 +1,153 @@
+use crate::balancer::Backend;
+use crate::BackendState;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// Passive outlier detection parameters
+#[derive(Debug, Clone)]
+pub struct OutlierConfig {
+    /// Failed or slow batches in a row after which a backend is ejected
+    pub consecutive_failures: u32,
+    /// Batches taking longer than this count as failures
+    pub max_latency: Duration,
+    /// Ejection time, doubled for every further ejection without a success in between
+    pub base_ejection: Duration,
+    /// Time over which a readmitted backend ramps back up to its full share of traffic
+    pub readmission: Duration,
+}
+
+/// What the router knows about the health of a backend
+#[derive(Debug)]
+pub(crate) struct Health {
+    /// Result of the last active health check
+    serving: bool,
+    consecutive_failures: u32,
+    /// Ejections in a row without a successful batch in between
+    ejections: u32,
+    /// End of the last ejection. Traffic ramps up again from there.
+    ejected_until: Option<Instant>,
+    /// Exponentially weighted moving average of the batch latency
+    latency: Option<Duration>,
+}
+
+impl Health {
+    pub fn new() -> Self {
+        Self {
+            serving: true,
+            consecutive_failures: 0,
+            ejections: 0,
+            ejected_until: None,
+            latency: None,
+        }
+    }
+
+    /// Share of its regular traffic the backend should get, between 0 and 1
+    pub fn weight(&self, now: Instant, config: &OutlierConfig) -> f64 {
+        if !self.serving {
+            return 0.0;
+        }
+        match self.ejected_until {
+            Some(until) if now < until => 0.0,
+            Some(until) if !config.readmission.is_zero() => {
+                ((now - until).as_secs_f64() / config.readmission.as_secs_f64()).min(1.0)
+            }
+            _ => 1.0,
+        }
+    }
+
+    pub fn state(&self, now: Instant, config: &OutlierConfig) -> BackendState {
+        if !self.serving {
+            return BackendState::NotServing;
+        }
+        match self.weight(now, config) {
+            w if w <= 0.0 => BackendState::Ejected,
+            w if w < 1.0 => BackendState::Recovering,
+            _ => BackendState::Healthy,
+        }
+    }
+
+    pub fn consecutive_failures(&self) -> u32 {
+        self.consecutive_failures
+    }
+
+    pub fn ejections(&self) -> u32 {
+        self.ejections
+    }
+
+    pub fn latency(&self) -> Option<Duration> {
+        self.latency
+    }
+
+    /// Record a batch answered after `latency`. Slow batches count as failures.
+    /// Returns the ejection time if the backend got ejected.
+    pub fn record_success(
+        &mut self,
+        latency: Duration,
+        config: &OutlierConfig,
+    ) -> Option<Duration> {
+        self.latency = Some(match self.latency {
+            Some(average) => average.mul_f64(0.8) + latency.mul_f64(0.2),
+            None => latency,
+        });
+        if latency > config.max_latency {
+            return self.record_failure(config);
+        }
+        self.consecutive_failures = 0;
+        self.ejections = 0;
+        None
+    }
+
+    /// Record a failed batch, ejecting the backend after too many in a row.
+    /// Returns the ejection time if the backend got ejected.
+    pub fn record_failure(&mut self, config: &OutlierConfig) -> Option<Duration> {
+        self.consecutive_failures += 1;
+        if self.consecutive_failures < config.consecutive_failures {
+            return None;
+        }
+
+        let ejection = config
+            .base_ejection
+            .saturating_mul(1 << self.ejections.min(3));
+        self.ejected_until = Some(Instant::now() + ejection);
+        self.ejections += 1;
+        self.consecutive_failures = 0;
+        Some(ejection)
+    }
+
+    /// Record the result of an active health check
+    fn record_probe(&mut self, serving: bool) {
+        if serving && !self.serving {
+            // Ramp up again as after an ejection
+            self.ejected_until = Some(Instant::now());
+        }
+        self.serving = serving;
+    }
+}
+
+/// Probe `backend` through `grpc.health.v1` every `interval`
+pub async fn health_check_task(backend: Arc<Backend>, interval: Duration) {
+    let mut client = backend.client.clone();
+    loop {
+        tokio::time::sleep(interval).await;
+
+        let serving = match tokio::time::timeout(interval, client.health_check()).await {
+            Ok(Ok(serving)) => serving,
+            Ok(Err(e)) => {
+                tracing::debug!("Health check of {} failed: {}", backend.address, e);
+                false
+            }
+            Err(_) => false,
+        };
+
+        let mut health = backend.health.lock().unwrap();
+        if serving != health.serving {
+            tracing::warn!(
+                "Backend {} is {}",
+                backend.address,
+                if serving { "serving" } else { "not serving" }
+            );
+        }
+        health.record_probe(serving);
+    }
+}
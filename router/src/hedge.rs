@@ -0,0 +1,80 @@
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Number of recent batch latencies the hedging delay is computed from
+const LATENCY_SAMPLES: usize = 128;
+/// Latencies needed before the first hedge, so that a cold start does not hedge at once
+const MIN_SAMPLES: usize = 16;
+/// Hedges that can be saved up while batches complete in time
+const MAX_BURST: f64 = 10.0;
+
+/// Hedged request parameters
+#[derive(Debug, Clone)]
+pub struct HedgeConfig {
+    /// Percentile of recent batch latencies after which a second backend gets the batch
+    pub percentile: f64,
+    /// Maximum share of batches that are hedged
+    pub max_ratio: f64,
+}
+
+/// Decides when a batch still waiting for its backend is sent to a second one, and
+/// keeps hedges below a share of the traffic
+#[derive(Debug)]
+pub struct Hedging {
+    config: HedgeConfig,
+    state: Mutex<State>,
+}
+
+#[derive(Debug)]
+struct State {
+    latencies: VecDeque<Duration>,
+    /// Hedges that may be sent, earned by every batch sent
+    budget: f64,
+}
+
+impl Hedging {
+    pub fn new(config: HedgeConfig) -> Self {
+        Self {
+            config,
+            state: Mutex::new(State {
+                latencies: VecDeque::with_capacity(LATENCY_SAMPLES),
+                budget: 0.0,
+            }),
+        }
+    }
+
+    /// Time after which a batch sent now is hedged, `None` until enough batches were
+    /// answered. Every call earns a share of a hedge.
+    pub fn delay(&self) -> Option<Duration> {
+        let mut state = self.state.lock().unwrap();
+        state.budget = (state.budget + self.config.max_ratio).min(MAX_BURST);
+        if state.latencies.len() < MIN_SAMPLES {
+            return None;
+        }
+        let mut sorted = state.latencies.iter().copied().collect::<Vec<_>>();
+        sorted.sort_unstable();
+        let rank = (sorted.len() as f64 * self.config.percentile / 100.0).ceil() as usize;
+        Some(sorted[rank.clamp(1, sorted.len()) - 1])
+    }
+
+    /// Spend a hedge, unless that would exceed the share of hedged batches
+    pub fn try_acquire(&self) -> bool {
+        let mut state = self.state.lock().unwrap();
+        if state.budget < 1.0 {
+            metrics::counter!("router_hedges_throttled_total").increment(1);
+            return false;
+        }
+        state.budget -= 1.0;
+        true
+    }
+
+    /// Record the latency of an answered batch
+    pub fn record(&self, latency: Duration) {
+        let mut state = self.state.lock().unwrap();
+        if state.latencies.len() == LATENCY_SAMPLES {
+            state.latencies.pop_front();
+        }
+        state.latencies.push_back(latency);
+    }
+}
@@ -1,6 +1,63 @@
-#[derive(serde::Deserialize, Debug, Clone)]
+mod adaptive;
+pub mod balancer;
+pub mod cache;
+pub mod dead_letter;
+pub mod health;
+pub mod hedge;
+pub mod policy;
+pub mod processor;
+pub mod queue;
+pub mod snapshot;
+pub mod wal;
+
+#[derive(serde::Deserialize, serde::Serialize, Debug, Clone, PartialEq, Eq, Hash)]
 pub struct TextReplyRequest {
     pub message: String,
+    /// Time in milliseconds the caller is willing to wait for the reply.
+    /// Falls back to the router default when unset.
+    #[serde(default)]
+    pub timeout_ms: Option<u64>,
+    /// Scheduling class of the request, `normal` when unset
+    #[serde(default)]
+    pub priority: Priority,
+    /// Who the request is made for, e.g. a Telegram chat. Tenants get a fair share of
+    /// every batch. Falls back to the `x-tenant-id` header.
+    #[serde(default)]
+    pub tenant: Option<String>,
+}
+
+/// Scheduling class of a request. Batches are filled from higher classes first.
+#[derive(
+    serde::Deserialize,
+    serde::Serialize,
+    Debug,
+    Default,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Hash,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum Priority {
+    /// Bulk jobs that can wait
+    Low,
+    #[default]
+    Normal,
+    /// Interactive traffic
+    High,
+}
+
+impl Priority {
+    /// Number of priority classes
+    pub const CLASSES: usize = 3;
+
+    /// Position of the class, from 0 for `low` to `CLASSES - 1` for `high`
+    pub fn rank(&self) -> usize {
+        *self as usize
+    }
 }
 
 #[derive(serde::Serialize, Debug, Clone)]
@@ -11,4 +68,62 @@ pub struct TextReplyResponse {
     pub batch_size: u32,
     pub processing_time: f32,
     pub other_responses: Vec<String>,
+    /// Whether the reply was served from the response cache without reaching a backend
+    pub from_cache: bool,
+}
+
+/// Outcome of one message of a `/process_messages` request
+#[derive(serde::Serialize, Debug, Clone)]
+pub struct MessageResult {
+    /// HTTP status the message would have gotten from `/process_message`
+    pub status: u16,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub response: Option<TextReplyResponse>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<ErrorResponse>,
+}
+
+#[derive(serde::Serialize, Debug, Clone)]
+pub struct CacheFlushResponse {
+    /// Number of cached replies dropped
+    pub flushed: usize,
+}
+
+#[derive(serde::Serialize, Debug, Clone)]
+pub struct DeadLetterPurgeResponse {
+    /// Number of dead letters dropped
+    pub purged: usize,
+}
+
+#[derive(serde::Serialize, Debug, Clone)]
+pub struct ErrorResponse {
+    pub error: String,
+    pub error_type: String,
+}
+
+#[derive(serde::Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum BackendState {
+    /// Gets its full share of batches
+    Healthy,
+    /// Temporarily ejected after too many failed or slow batches
+    Ejected,
+    /// Readmitted after an ejection, traffic is ramping up
+    Recovering,
+    /// Failed its last active health check
+    NotServing,
+}
+
+#[derive(serde::Serialize, Debug, Clone)]
+pub struct BackendStatus {
+    pub address: String,
+    pub state: BackendState,
+    pub in_flight: usize,
+    pub consecutive_failures: u32,
+    pub ejections: u32,
+    pub latency_ms: Option<f64>,
+    /// State of the circuit breaker: closed, open or half_open
+    pub circuit: String,
+    /// Largest batch sent to the backend, learned from batches it rejected as too large
+    pub batch_size_cap: Option<usize>,
 }
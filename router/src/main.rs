@@ -1,21 +1,174 @@
-mod processor;
-mod queue;
-
 use anyhow::Result;
-use axum::{http::StatusCode, routing::post, Json, Router};
+use axum::{
+    extract::Path,
+    http::{HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    routing::{get, post},
+    Json, Router,
+};
 use clap::Parser;
+use metrics_exporter_prometheus::PrometheusBuilder;
+use reply_client::CircuitBreakerConfig;
+use std::future::IntoFuture;
+use std::path::PathBuf;
+use std::time::Duration;
 
 use tokio::net::TcpListener;
+use tokio::signal;
+use tokio::sync::oneshot;
 use tonic::transport::Uri;
 
-use router::{TextReplyRequest, TextReplyResponse};
+use router::balancer::{Backend, Balancer, LoadBalancing};
+use router::cache::CacheConfig;
+use router::health::OutlierConfig;
+use router::hedge::HedgeConfig;
+use router::policy::{BatchingConfig, BudgetUnit, PolicyKind};
+use router::processor::{self, ProcessorConfig, ProcessorError, RetryConfig};
+use router::queue::{Bucketing, QueueConfig, TenantWeights};
+use router::snapshot;
+use router::wal::Wal;
+use router::{
+    CacheFlushResponse, DeadLetterPurgeResponse, ErrorResponse, MessageResult, TextReplyRequest,
+    TextReplyResponse,
+};
 
 #[derive(Parser, Debug)]
 struct Args {
     #[clap(short, long, default_value = "127.0.0.1:8080")]
     address: String,
-    #[clap(short, long, default_value = "127.0.0.1:50051")]
-    grpc_address: String,
+    /// gRPC server to send batches to. Repeat the flag to balance over several servers.
+    /// Defaults to 127.0.0.1:50051 if neither this nor `--backends-file` is given.
+    #[clap(short, long)]
+    grpc_address: Vec<String>,
+    /// File with one gRPC server address per line, in addition to `--grpc-address`
+    #[clap(long, env)]
+    backends_file: Option<PathBuf>,
+    /// How batches are spread over the gRPC servers
+    #[clap(long, env, value_enum, default_value_t = LoadBalancing::RoundRobin)]
+    load_balancing: LoadBalancing,
+    /// Interval in milliseconds between `grpc.health.v1` checks of every backend
+    #[clap(long, env, default_value = "1000")]
+    health_check_interval_ms: u64,
+    /// Failed or slow batches in a row after which a backend is ejected
+    #[clap(long, env, default_value = "5")]
+    outlier_consecutive_failures: u32,
+    /// Batches slower than this many milliseconds count as failures
+    #[clap(long, env, default_value = "5000")]
+    outlier_max_latency_ms: u64,
+    /// Ejection time in milliseconds, doubled for repeated ejections
+    #[clap(long, env, default_value = "5000")]
+    outlier_ejection_ms: u64,
+    /// Time in milliseconds over which a readmitted backend ramps back up to full traffic
+    #[clap(long, env, default_value = "10000")]
+    readmission_ms: u64,
+    /// Failed calls in a row after which a backend's circuit breaker opens
+    #[clap(long, env, default_value = "5")]
+    circuit_failure_threshold: u32,
+    /// Time in milliseconds an open circuit fails fast before trial calls are let through
+    #[clap(long, env, default_value = "10000")]
+    circuit_cool_down_ms: u64,
+    /// Number of trial calls allowed at the same time while a circuit is half-open
+    #[clap(long, env, default_value = "1")]
+    circuit_half_open_calls: u32,
+    /// Maximum number of requests in one batch
+    #[clap(long, env, default_value = "32")]
+    max_batch_size: usize,
+    /// Maximum time in milliseconds the oldest request waits for its batch to fill up
+    #[clap(long, env, default_value = "50")]
+    max_wait_ms: u64,
+    /// Policy deciding when a batch is cut and what goes into it
+    #[clap(long, env, value_enum, default_value_t = PolicyKind::SizeOrTimeout)]
+    batch_policy: PolicyKind,
+    /// Maximum total message length of a batch (budget policy only).
+    /// Larger requests are rejected.
+    #[clap(long, env, default_value = "65536")]
+    max_batch_budget: usize,
+    /// Unit of `--max-batch-budget` and `--length-buckets`
+    #[clap(long, env, value_enum, default_value_t = BudgetUnit::Bytes)]
+    budget_unit: BudgetUnit,
+    /// Comma separated message lengths at which the queue is split into buckets.
+    /// A batch only holds requests from one bucket. Empty to disable bucketing.
+    #[clap(long, env, value_delimiter = ',')]
+    length_buckets: Vec<usize>,
+    /// Time in milliseconds after which a waiting request is served before any other
+    /// bucket
+    #[clap(long, env, default_value = "1000")]
+    starvation_ms: u64,
+    /// Time in milliseconds a request waits before it counts as one priority class
+    /// higher. 0 disables aging.
+    #[clap(long, env, default_value = "500")]
+    priority_aging_ms: u64,
+    /// Comma separated `tenant=weight` pairs. A tenant with weight 2 gets twice the
+    /// batch slots of a tenant with the default weight of 1 while both have requests queued.
+    /// Only these tenants get their own `router_tenant_queue_depth` series, the others
+    /// are reported as `other`.
+    #[clap(long, env, value_delimiter = ',', value_parser = parse_tenant_weight)]
+    tenant_weights: Vec<(String, usize)>,
+    /// Send requests with the same message and parameters (timeout, priority and
+    /// tenant) that are waiting at the same time to the gRPC server only once and hand
+    /// the reply to all of them
+    #[clap(long, env)]
+    dedup: bool,
+    /// p99 latency in milliseconds the adaptive policy aims for. The window never
+    /// exceeds `--max-wait-ms` and batches never exceed `--max-batch-size`.
+    #[clap(long, env, default_value = "200")]
+    latency_target_ms: u64,
+    /// Maximum number of replies kept in the response cache. 0 disables the cache.
+    #[clap(long, env, default_value = "0")]
+    cache_capacity: usize,
+    /// Time in milliseconds a cached reply stays valid
+    #[clap(long, env, default_value = "60000")]
+    cache_ttl_ms: u64,
+    /// Send a batch to a second backend as well once it has waited longer than this
+    /// percentile of recent batch latencies, e.g. 95. Hedging is off unless set and
+    /// needs at least two backends.
+    #[clap(long, env)]
+    hedge_percentile: Option<f64>,
+    /// Maximum share of batches that get hedged
+    #[clap(long, env, default_value = "0.05")]
+    hedge_max_ratio: f64,
+    /// Maximum number of failed requests kept for inspection and replay under
+    /// `/admin/dead_letters`. 0 disables the dead-letter store.
+    #[clap(long, env, default_value = "1000")]
+    dead_letter_capacity: usize,
+    /// Log queued requests to this file and queue the ones that did not get a reply
+    /// again after a restart. Without it, queued requests are lost with the process.
+    /// Their replies only go to the response cache, see `--cache-capacity`.
+    #[clap(long, env)]
+    wal_path: Option<PathBuf>,
+    /// Time in milliseconds the router keeps processing queued and in-flight requests
+    /// after SIGTERM or Ctrl-C, without accepting new ones
+    #[clap(long, env, default_value = "10000")]
+    shutdown_timeout_ms: u64,
+    /// Write the requests without a response when the shutdown timeout expires to this
+    /// file, and queue them again when the router starts with the same path. Their
+    /// callers get HTTP 503 and find the reply in the response cache when they send
+    /// the request again, see `--cache-capacity`.
+    #[clap(long, env)]
+    snapshot_path: Option<PathBuf>,
+    /// Deadline in milliseconds for requests that do not set `timeout_ms`
+    #[clap(long, env, default_value = "30000")]
+    request_timeout_ms: u64,
+    /// Maximum number of requests waiting in the queue. Further requests get HTTP 429.
+    #[clap(long, env, default_value = "1024")]
+    max_queue_depth: usize,
+    /// Maximum number of messages in one `/process_messages` request. Larger arrays
+    /// get HTTP 413. Messages that do not fit into the queue get 429 each, so keep it
+    /// well below `--max-queue-depth`.
+    #[clap(long, env, default_value = "256")]
+    max_messages_per_request: usize,
+    /// Maximum number of batches in flight to the gRPC server at the same time
+    #[clap(long, env, default_value = "4")]
+    max_concurrent_batches: usize,
+    /// Number of times a request is resent after a retryable gRPC error
+    #[clap(long, env, default_value = "3")]
+    max_retries: u32,
+    /// Backoff in milliseconds before the first retry, doubled for every further attempt
+    #[clap(long, env, default_value = "50")]
+    retry_base_backoff_ms: u64,
+    /// Upper bound in milliseconds of the retry backoff
+    #[clap(long, env, default_value = "2000")]
+    retry_max_backoff_ms: u64,
 }
 
 #[tokio::main]
@@ -26,60 +179,380 @@ async fn main() -> Result<()> {
 
     let args = Args::parse();
     tracing::info!("args: {:?}", &args);
+    anyhow::ensure!(args.max_batch_size > 0, "--max-batch-size must be positive");
+    anyhow::ensure!(
+        args.max_concurrent_batches > 0,
+        "--max-concurrent-batches must be positive"
+    );
+    anyhow::ensure!(
+        args.length_buckets.windows(2).all(|w| w[0] < w[1]),
+        "--length-buckets must be in ascending order"
+    );
+    anyhow::ensure!(
+        args.hedge_percentile
+            .map_or(true, |p| p > 0.0 && p <= 100.0),
+        "--hedge-percentile must be in (0, 100]"
+    );
+    anyhow::ensure!(
+        (0.0..=1.0).contains(&args.hedge_max_ratio),
+        "--hedge-max-ratio must be between 0 and 1"
+    );
 
-    let uri = Uri::builder()
-        .scheme("http")
-        .authority(args.grpc_address.clone())
-        .path_and_query("/")
-        .build()
-        .unwrap();
-    let client = reply_client::Client::new(uri.clone()).await?;
-    let proc = processor::Processor::new(client);
-
-    tracing::info!("Connected to gRPC server at {}", args.grpc_address);
-
-    let app = Router::new().route(
-        "/process_message",
-        post(move |echo_request: Json<TextReplyRequest>| {
-            message_handler(echo_request, proc.clone())
+    let mut addresses = args.grpc_address.clone();
+    if let Some(path) = &args.backends_file {
+        addresses.extend(read_backends_file(path)?);
+    }
+    if addresses.is_empty() {
+        addresses.push("127.0.0.1:50051".to_string());
+    }
+
+    let circuit_config = CircuitBreakerConfig {
+        failure_threshold: args.circuit_failure_threshold,
+        cool_down: Duration::from_millis(args.circuit_cool_down_ms),
+        half_open_max_calls: args.circuit_half_open_calls,
+    };
+    let mut backends = Vec::with_capacity(addresses.len());
+    for address in addresses {
+        let uri = Uri::builder()
+            .scheme("http")
+            .authority(address.clone())
+            .path_and_query("/")
+            .build()?;
+        // Unreachable backends are left to the health checks and ejection
+        let client = reply_client::Client::new(uri).with_circuit_breaker(circuit_config.clone());
+        tracing::info!("Added gRPC server at {}", address);
+        backends.push(Backend::new(address, client));
+    }
+    let outlier_config = OutlierConfig {
+        consecutive_failures: args.outlier_consecutive_failures,
+        max_latency: Duration::from_millis(args.outlier_max_latency_ms),
+        base_ejection: Duration::from_millis(args.outlier_ejection_ms),
+        readmission: Duration::from_millis(args.readmission_ms),
+    };
+    let balancer = Balancer::new(backends, args.load_balancing, outlier_config);
+    balancer.spawn_health_checks(Duration::from_millis(args.health_check_interval_ms));
+    let batching_config = BatchingConfig {
+        max_batch_size: args.max_batch_size,
+        max_wait: Duration::from_millis(args.max_wait_ms),
+        max_batch_budget: args.max_batch_budget,
+        budget_unit: args.budget_unit,
+        latency_target: Duration::from_millis(args.latency_target_ms),
+    };
+    if (args.wal_path.is_some() || args.snapshot_path.is_some()) && args.cache_capacity == 0 {
+        tracing::warn!(
+            "The response cache is disabled, so replies to requests queued again after a \
+             restart are lost. Set --cache-capacity to keep them for callers that resend."
+        );
+    }
+    let (wal, recovered) = match &args.wal_path {
+        Some(path) => {
+            let (wal, recovered) = Wal::open(path)?;
+            (Some(wal), recovered)
+        }
+        None => (None, Vec::new()),
+    };
+    let shutdown_wal = wal.clone();
+    let processor_config = ProcessorConfig {
+        default_timeout: Duration::from_millis(args.request_timeout_ms),
+        max_concurrent_batches: args.max_concurrent_batches,
+        retry: RetryConfig {
+            max_retries: args.max_retries,
+            base_backoff: Duration::from_millis(args.retry_base_backoff_ms),
+            max_backoff: Duration::from_millis(args.retry_max_backoff_ms),
+        },
+        queue: QueueConfig {
+            max_depth: args.max_queue_depth,
+            bucketing: Bucketing {
+                boundaries: args.length_buckets.clone(),
+                unit: args.budget_unit,
+                starvation: Duration::from_millis(args.starvation_ms),
+            },
+            priority_aging: Duration::from_millis(args.priority_aging_ms),
+            tenant_weights: TenantWeights(args.tenant_weights.iter().cloned().collect()),
+            dedup: args.dedup,
+        },
+        cache: CacheConfig {
+            capacity: args.cache_capacity,
+            ttl: Duration::from_millis(args.cache_ttl_ms),
+        },
+        dead_letter_capacity: args.dead_letter_capacity,
+        wal,
+        hedge: args.hedge_percentile.map(|percentile| HedgeConfig {
+            percentile,
+            max_ratio: args.hedge_max_ratio,
         }),
+    };
+    let proc = processor::Processor::new(
+        balancer,
+        batching_config.policy(args.batch_policy),
+        processor_config,
     );
+    if !recovered.is_empty() {
+        tracing::info!(
+            "Recovering {} requests from the write-ahead log",
+            recovered.len()
+        );
+        proc.recover(recovered);
+    }
+    if let Some(path) = &args.snapshot_path {
+        let pending = snapshot::load(path)?;
+        if !pending.is_empty() {
+            tracing::info!("Restoring {} requests from the snapshot", pending.len());
+            proc.restore_snapshot(pending);
+        }
+    }
+
+    let prom_handle = PrometheusBuilder::new().install_recorder()?;
+
+    let shutdown_proc = proc.clone();
+    let messages_proc = proc.clone();
+    let admin_proc = proc.clone();
+    let cache_proc = proc.clone();
+    let dead_letter_proc = proc.clone();
+    let app = Router::new()
+        .route(
+            "/process_message",
+            post(
+                move |headers: HeaderMap, echo_request: Json<TextReplyRequest>| {
+                    message_handler(headers, echo_request, proc.clone())
+                },
+            ),
+        )
+        .route(
+            "/process_messages",
+            post(
+                move |headers: HeaderMap, requests: Json<Vec<TextReplyRequest>>| {
+                    messages_handler(
+                        headers,
+                        requests,
+                        messages_proc.clone(),
+                        args.max_messages_per_request,
+                    )
+                },
+            ),
+        )
+        .route(
+            "/admin/backends",
+            get(move || async move { Json(admin_proc.backend_status()) }),
+        )
+        .route(
+            "/admin/cache/flush",
+            post(move || async move {
+                let flushed = cache_proc.flush_cache();
+                tracing::info!("Flushed {} cached replies", flushed);
+                Json(CacheFlushResponse { flushed })
+            }),
+        )
+        .nest("/admin/dead_letters", dead_letter_routes(dead_letter_proc))
+        .route("/metrics", get(move || async move { prom_handle.render() }));
 
     tracing::info!("Listening on {}", &args.address);
     let listener = TcpListener::bind(&args.address).await.unwrap();
 
-    axum::serve(listener, app).await.unwrap();
+    let (signal_sender, signal_receiver) = oneshot::channel();
+    let server = axum::serve(listener, app).with_graceful_shutdown(async move {
+        shutdown_signal().await;
+        let _ = signal_sender.send(());
+    });
+    let server = tokio::spawn(server.into_future());
+    // Also returns if the server stops on its own
+    let _ = signal_receiver.await;
+
+    let timeout = Duration::from_millis(args.shutdown_timeout_ms);
+    tracing::info!("Shutting down, draining requests for up to {:?}", timeout);
+    let drain = async {
+        // The server stops once every open request got its response
+        match server.await {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => tracing::error!("Server failed: {}", e),
+            Err(e) => tracing::error!("Server task failed: {}", e),
+        }
+        shutdown_proc.wait_idle().await;
+    };
+    if tokio::time::timeout(timeout, drain).await.is_err() {
+        tracing::warn!("Shutdown timeout expired with requests still pending");
+    }
+
+    let pending = shutdown_proc.shutdown().await;
+    match &args.snapshot_path {
+        _ if pending.is_empty() => {}
+        Some(path) => match snapshot::save(path, pending) {
+            Ok(saved) => tracing::info!("Saved {} requests to {}", saved, path.display()),
+            Err(e) => tracing::error!("Failed to write the snapshot: {}", e),
+        },
+        None => tracing::warn!("Shutting down with {} unanswered requests", pending.len()),
+    }
+    if let Some(wal) = shutdown_wal {
+        // Records still on their way to the file would be lost with the process
+        tokio::task::spawn_blocking(move || wal.flush()).await?;
+    }
     tracing::info!("Server shutdown");
 
     Ok(())
 }
 
+/// Resolves on SIGTERM or Ctrl-C
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        signal::ctrl_c().await.unwrap();
+    };
+    let terminate = async {
+        signal::unix::signal(signal::unix::SignalKind::terminate())
+            .unwrap()
+            .recv()
+            .await;
+    };
+    tokio::select! {
+        _ = ctrl_c => {}
+        _ = terminate => {}
+    }
+}
+
+/// List, inspect, replay and purge requests that failed for good
+fn dead_letter_routes(processor: processor::Processor) -> Router {
+    let list_proc = processor.clone();
+    let purge_proc = processor.clone();
+    let get_proc = processor.clone();
+    let delete_proc = processor.clone();
+    Router::new()
+        .route(
+            "/",
+            get(move || async move { Json(list_proc.dead_letters().list()) }).delete(
+                move || async move {
+                    let purged = purge_proc.dead_letters().purge();
+                    tracing::info!("Purged {} dead letters", purged);
+                    Json(DeadLetterPurgeResponse { purged })
+                },
+            ),
+        )
+        .route(
+            "/:id",
+            get(move |Path(id): Path<u64>| async move {
+                get_proc
+                    .dead_letters()
+                    .get(id)
+                    .map(Json)
+                    .ok_or_else(|| dead_letter_not_found(id))
+            })
+            .delete(move |Path(id): Path<u64>| async move {
+                delete_proc
+                    .dead_letters()
+                    .take(id)
+                    .map(|_| StatusCode::NO_CONTENT)
+                    .ok_or_else(|| dead_letter_not_found(id))
+            }),
+        )
+        .route(
+            "/:id/replay",
+            post(move |Path(id): Path<u64>| async move {
+                match processor.replay_dead_letter(id).await {
+                    Some(Ok(response)) => Ok(Json(response)),
+                    Some(Err(e)) => Err(e.into_response()),
+                    None => Err(dead_letter_not_found(id)),
+                }
+            }),
+        )
+}
+
+fn dead_letter_not_found(id: u64) -> Response {
+    let body = Json(ErrorResponse {
+        error: format!("No dead letter with id {id}"),
+        error_type: "not_found".to_string(),
+    });
+    (StatusCode::NOT_FOUND, body).into_response()
+}
+
+fn parse_tenant_weight(s: &str) -> Result<(String, usize), String> {
+    let (tenant, weight) = s
+        .split_once('=')
+        .ok_or_else(|| format!("expected `tenant=weight`, got `{s}`"))?;
+    let weight = weight
+        .parse()
+        .map_err(|e| format!("invalid weight `{weight}`: {e}"))?;
+    Ok((tenant.to_string(), weight))
+}
+
+/// Read backend addresses from a file, one per line. Blank lines and `#` comments are skipped.
+fn read_backends_file(path: &PathBuf) -> Result<Vec<String>> {
+    let content = std::fs::read_to_string(path)?;
+    Ok(content
+        .lines()
+        .map(|line| line.split('#').next().unwrap_or("").trim())
+        .filter(|line| !line.is_empty())
+        .map(str::to_string)
+        .collect())
+}
+
+/// Header naming the tenant of requests that do not set `tenant` themselves
+const TENANT_HEADER: &str = "x-tenant-id";
+
 async fn message_handler(
+    headers: HeaderMap,
     request: Json<TextReplyRequest>,
     processor: processor::Processor,
-) -> Result<Json<TextReplyResponse>, StatusCode> {
-    let request = request.0;
+) -> Result<Json<TextReplyResponse>, ProcessorError> {
+    let mut request = request.0;
+    set_tenant(&mut request, &headers);
 
     tracing::info!("Processing request: {:?}", &request);
 
-    let mut response_rx = processor.process_request(request).await.map_err(|e| {
+    let response = processor.process_request(request).await.map_err(|e| {
         tracing::error!("Error: {:?}", e);
-        StatusCode::INTERNAL_SERVER_ERROR
+        e
     })?;
 
-    let response = match response_rx.recv().await {
-        Some(response) => response,
-        None => return Err(StatusCode::INTERNAL_SERVER_ERROR),
-    };
+    Ok(Json(response))
+}
 
-    let json_response = TextReplyResponse {
-        message: response.message,
-        batch_id: response.batch_id,
-        request_id: response.request_id,
-        batch_size: response.batch_id,
-        processing_time: response.processing_time,
-        other_responses: response.other_responses,
-    };
+/// Process several messages at once. Every message gets its own status, in the order
+/// of the request. Requests of more than `limit` messages are rejected as a whole.
+async fn messages_handler(
+    headers: HeaderMap,
+    requests: Json<Vec<TextReplyRequest>>,
+    processor: processor::Processor,
+    limit: usize,
+) -> Result<Json<Vec<MessageResult>>, ProcessorError> {
+    let mut requests = requests.0;
+    if requests.len() > limit {
+        return Err(ProcessorError::TooManyMessages {
+            count: requests.len(),
+            limit,
+        });
+    }
+    for request in &mut requests {
+        set_tenant(request, &headers);
+    }
+
+    tracing::info!("Processing {} requests", requests.len());
+
+    let results = processor.process_requests(requests).await;
+    let results = results
+        .into_iter()
+        .map(|result| match result {
+            Ok(response) => MessageResult {
+                status: StatusCode::OK.as_u16(),
+                response: Some(response),
+                error: None,
+            },
+            Err(e) => {
+                tracing::error!("Error: {:?}", e);
+                MessageResult {
+                    status: e.status_code().as_u16(),
+                    response: None,
+                    error: Some(e.to_error_response()),
+                }
+            }
+        })
+        .collect();
+    Ok(Json(results))
+}
 
-    Ok(Json(json_response))
+/// Requests that do not name a tenant belong to the one of the tenant header
+fn set_tenant(request: &mut TextReplyRequest, headers: &HeaderMap) {
+    if request.tenant.is_none() {
+        request.tenant = headers
+            .get(TENANT_HEADER)
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_string);
+    }
 }
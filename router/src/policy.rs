@@ -0,0 +1,215 @@
+use crate::adaptive::Adaptive;
+use crate::processor::ProcessorError;
+use crate::TextReplyRequest;
+use std::fmt::{Debug, Display};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// Running totals of a batch being assembled
+#[derive(Debug, Default, Clone, Copy)]
+pub struct BatchUsage {
+    pub requests: usize,
+    /// Sum of [`BatchPolicy::cost`] over the requests in the batch
+    pub cost: usize,
+}
+
+impl BatchUsage {
+    pub fn add(&mut self, cost: usize) {
+        self.requests += 1;
+        self.cost += cost;
+    }
+}
+
+/// Decides when the queue cuts a batch and what goes into it.
+///
+/// The queue walks its entries oldest first and asks [`BatchPolicy::fits`] for every
+/// entry after the first one. The batch is dispatched right away if it is full,
+/// otherwise once the [`BatchPolicy::deadline`] of its oldest entry has passed.
+pub trait BatchPolicy: Debug + Send + Sync {
+    /// Reject requests that could never be batched under this policy
+    fn admit(&self, _request: &TextReplyRequest) -> Result<(), ProcessorError> {
+        Ok(())
+    }
+
+    /// Cost of a request against the batch budget
+    fn cost(&self, _request: &TextReplyRequest) -> usize {
+        0
+    }
+
+    /// Whether a request costing `cost` can join a batch that already holds `usage`
+    fn fits(&self, usage: &BatchUsage, cost: usize) -> bool;
+
+    /// Whether a batch holding `usage` should be dispatched without waiting any longer
+    fn is_full(&self, usage: &BatchUsage) -> bool;
+
+    /// Instant at which a batch whose oldest entry was queued at `oldest` is dispatched
+    fn deadline(&self, oldest: Instant) -> Instant;
+
+    /// Called for every request accepted into the queue
+    fn record_arrival(&self, _now: Instant) {}
+
+    /// Called for every batch of `size` requests the backend answered after `latency`
+    fn record_batch(&self, _size: usize, _latency: Duration) {}
+}
+
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PolicyKind {
+    /// Wait for the whole window after the first request, then send up to `max_batch_size`
+    FixedWindow,
+    /// Send when `max_batch_size` requests are queued or the window has passed
+    SizeOrTimeout,
+    /// Like `size-or-timeout`, but also cap the total message length of a batch
+    Budget,
+    /// Adjust the window and the batch size to the load to meet `latency_target`
+    Adaptive,
+}
+
+/// How the budget policy measures message length
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BudgetUnit {
+    /// UTF-8 length of the message
+    Bytes,
+    /// Number of whitespace separated words, a rough estimate of the token count
+    Tokens,
+}
+
+impl BudgetUnit {
+    pub fn measure(&self, message: &str) -> usize {
+        match self {
+            BudgetUnit::Bytes => message.len(),
+            BudgetUnit::Tokens => message.split_whitespace().count(),
+        }
+    }
+}
+
+impl Display for BudgetUnit {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BudgetUnit::Bytes => write!(f, "bytes"),
+            BudgetUnit::Tokens => write!(f, "tokens"),
+        }
+    }
+}
+
+/// Parameters shared by all batching policies
+#[derive(Debug, Clone)]
+pub struct BatchingConfig {
+    /// Maximum number of requests sent to the backend in one batch
+    pub max_batch_size: usize,
+    /// Maximum time the oldest queued request waits before its batch is dispatched
+    pub max_wait: Duration,
+    /// Maximum total message length of a batch, used by the budget policy
+    pub max_batch_budget: usize,
+    /// Unit of `max_batch_budget`
+    pub budget_unit: BudgetUnit,
+    /// p99 latency the adaptive policy aims for
+    pub latency_target: Duration,
+}
+
+impl BatchingConfig {
+    pub fn policy(&self, kind: PolicyKind) -> Arc<dyn BatchPolicy> {
+        match kind {
+            PolicyKind::FixedWindow => Arc::new(FixedWindow {
+                max_batch_size: self.max_batch_size,
+                window: self.max_wait,
+            }),
+            PolicyKind::SizeOrTimeout => Arc::new(SizeOrTimeout {
+                max_batch_size: self.max_batch_size,
+                max_wait: self.max_wait,
+            }),
+            PolicyKind::Budget => Arc::new(Budget {
+                max_batch_size: self.max_batch_size,
+                max_batch_budget: self.max_batch_budget,
+                unit: self.budget_unit,
+                max_wait: self.max_wait,
+            }),
+            PolicyKind::Adaptive => Arc::new(Adaptive::new(
+                self.max_batch_size,
+                self.max_wait,
+                self.latency_target,
+            )),
+        }
+    }
+}
+
+/// Always waits for the full window, however many requests arrive in the meantime
+#[derive(Debug)]
+pub struct FixedWindow {
+    max_batch_size: usize,
+    window: Duration,
+}
+
+impl BatchPolicy for FixedWindow {
+    fn fits(&self, usage: &BatchUsage, _cost: usize) -> bool {
+        usage.requests < self.max_batch_size
+    }
+
+    fn is_full(&self, _usage: &BatchUsage) -> bool {
+        false
+    }
+
+    fn deadline(&self, oldest: Instant) -> Instant {
+        oldest + self.window
+    }
+}
+
+/// Dispatches as soon as the batch is full or its oldest entry has waited for `max_wait`
+#[derive(Debug)]
+pub struct SizeOrTimeout {
+    max_batch_size: usize,
+    max_wait: Duration,
+}
+
+impl BatchPolicy for SizeOrTimeout {
+    fn fits(&self, usage: &BatchUsage, _cost: usize) -> bool {
+        usage.requests < self.max_batch_size
+    }
+
+    fn is_full(&self, usage: &BatchUsage) -> bool {
+        usage.requests >= self.max_batch_size
+    }
+
+    fn deadline(&self, oldest: Instant) -> Instant {
+        oldest + self.max_wait
+    }
+}
+
+/// Size-or-timeout that additionally caps the total message length of a batch
+#[derive(Debug)]
+pub struct Budget {
+    max_batch_size: usize,
+    max_batch_budget: usize,
+    unit: BudgetUnit,
+    max_wait: Duration,
+}
+
+impl BatchPolicy for Budget {
+    fn admit(&self, request: &TextReplyRequest) -> Result<(), ProcessorError> {
+        let size = self.unit.measure(&request.message);
+        if size > self.max_batch_budget {
+            return Err(ProcessorError::TooLarge {
+                size,
+                limit: self.max_batch_budget,
+                unit: self.unit,
+            });
+        }
+        Ok(())
+    }
+
+    fn cost(&self, request: &TextReplyRequest) -> usize {
+        self.unit.measure(&request.message)
+    }
+
+    fn fits(&self, usage: &BatchUsage, cost: usize) -> bool {
+        usage.requests < self.max_batch_size && usage.cost + cost <= self.max_batch_budget
+    }
+
+    fn is_full(&self, usage: &BatchUsage) -> bool {
+        usage.requests >= self.max_batch_size || usage.cost >= self.max_batch_budget
+    }
+
+    fn deadline(&self, oldest: Instant) -> Instant {
+        oldest + self.max_wait
+    }
+}
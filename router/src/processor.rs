@@ -1,80 +1,954 @@
 use crate::{
-    queue::{Queue, QueueEntry},
+    balancer::{Backend, BackendGuard, Balancer},
+    cache::{CacheConfig, ResponseCache},
+    dead_letter::{DeadLetters, FailedAttempt},
+    hedge::{HedgeConfig, Hedging},
+    policy::{BatchPolicy, BudgetUnit},
+    queue::{Queue, QueueConfig, QueueEntry, ReadyBatch, ResponseRx},
+    snapshot::Pending,
+    wal::{Recovered, Wal, WalTicket},
     TextReplyRequest, TextReplyResponse,
 };
-use reply_client::Client;
+use crate::{BackendStatus, ErrorResponse};
+use axum::{
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use reply_client::{ClientBatch, ClientError, ReplyResponse};
 
-use anyhow::Result;
+use std::collections::{HashMap, HashSet};
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
-use tokio::sync::{mpsc, Notify};
-use tracing::instrument;
+use std::time::{Duration, SystemTime};
+use thiserror::Error;
+use tokio::sync::{mpsc, watch, OwnedSemaphorePermit};
+use tokio::time::Instant;
+use tonic::{Code, Status};
+use tracing::{instrument, Instrument};
+
+#[derive(Debug, Clone, Error)]
+pub enum ProcessorError {
+    #[error("Request of {size} {unit} exceeds the batch budget of {limit} {unit}")]
+    TooLarge {
+        size: usize,
+        limit: usize,
+        unit: BudgetUnit,
+    },
+    #[error("Request of {count} messages exceeds the limit of {limit} messages")]
+    TooManyMessages { count: usize, limit: usize },
+    #[error("Queue is full, try again later")]
+    Overloaded,
+    #[error("Request was not answered before its deadline")]
+    Timeout,
+    #[error("Backend failed to process the request: {0}")]
+    Backend(String),
+    #[error("Backend is unavailable: {0}")]
+    Unavailable(String),
+    #[error("Backend rejected the request: {message}")]
+    Rejected { code: Code, message: String },
+    #[error("Backend returned no response for the request")]
+    MissingResponse,
+    #[error("Request was dropped before a response was received")]
+    Dropped,
+}
+
+impl ProcessorError {
+    pub fn error_type(&self) -> &'static str {
+        match self {
+            ProcessorError::TooLarge { .. } => "too_large",
+            ProcessorError::TooManyMessages { .. } => "too_many_messages",
+            ProcessorError::Overloaded => "overloaded",
+            ProcessorError::Timeout => "timeout",
+            ProcessorError::Backend(_) => "backend",
+            ProcessorError::Unavailable(_) => "unavailable",
+            ProcessorError::Rejected { .. } => "rejected",
+            ProcessorError::MissingResponse => "missing_response",
+            ProcessorError::Dropped => "dropped",
+        }
+    }
+
+    /// HTTP status a request failing with this error gets
+    pub fn status_code(&self) -> StatusCode {
+        match self {
+            ProcessorError::TooLarge { .. } | ProcessorError::TooManyMessages { .. } => {
+                StatusCode::PAYLOAD_TOO_LARGE
+            }
+            ProcessorError::Overloaded => StatusCode::TOO_MANY_REQUESTS,
+            ProcessorError::Timeout => StatusCode::GATEWAY_TIMEOUT,
+            ProcessorError::Unavailable(_) => StatusCode::SERVICE_UNAVAILABLE,
+            ProcessorError::Rejected { code, .. } => match code {
+                Code::InvalidArgument | Code::FailedPrecondition | Code::OutOfRange => {
+                    StatusCode::BAD_REQUEST
+                }
+                Code::NotFound => StatusCode::NOT_FOUND,
+                Code::PermissionDenied => StatusCode::FORBIDDEN,
+                Code::Unauthenticated => StatusCode::UNAUTHORIZED,
+                Code::ResourceExhausted => StatusCode::TOO_MANY_REQUESTS,
+                Code::Unavailable => StatusCode::SERVICE_UNAVAILABLE,
+                Code::DeadlineExceeded => StatusCode::GATEWAY_TIMEOUT,
+                Code::Unimplemented => StatusCode::NOT_IMPLEMENTED,
+                _ => StatusCode::INTERNAL_SERVER_ERROR,
+            },
+            ProcessorError::Backend(_) | ProcessorError::MissingResponse => StatusCode::BAD_GATEWAY,
+            ProcessorError::Dropped => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    pub fn to_error_response(&self) -> ErrorResponse {
+        ErrorResponse {
+            error: self.to_string(),
+            error_type: self.error_type().to_string(),
+        }
+    }
+}
+
+/// Seconds a client should back off after a 429
+const RETRY_AFTER_SECS: u64 = 1;
+
+impl IntoResponse for ProcessorError {
+    fn into_response(self) -> Response {
+        let status_code = self.status_code();
+        let body = Json(self.to_error_response());
+
+        match self {
+            ProcessorError::Overloaded => (
+                status_code,
+                [(header::RETRY_AFTER, RETRY_AFTER_SECS.to_string())],
+                body,
+            )
+                .into_response(),
+            _ => (status_code, body).into_response(),
+        }
+    }
+}
+
+/// Router-wide request handling parameters
+#[derive(Debug, Clone)]
+pub struct ProcessorConfig {
+    /// Deadline applied to requests that do not set `timeout_ms`
+    pub default_timeout: Duration,
+    /// Maximum number of batches sent to the backend at the same time
+    pub max_concurrent_batches: usize,
+    pub retry: RetryConfig,
+    pub queue: QueueConfig,
+    pub cache: CacheConfig,
+    /// Maximum number of failed requests kept for inspection and replay. Zero
+    /// disables the dead-letter store.
+    pub dead_letter_capacity: usize,
+    /// Send slow batches to a second backend as well. `None` disables hedging.
+    pub hedge: Option<HedgeConfig>,
+    /// Log of queued requests that outlives the process. `None` keeps the queue in
+    /// memory only.
+    pub wal: Option<Arc<Wal>>,
+}
+
+/// How batches failing with a retryable backend error are resent
+#[derive(Debug, Clone)]
+pub struct RetryConfig {
+    /// Number of times a request is resent before it fails
+    pub max_retries: u32,
+    /// Backoff before the first retry, doubled for every further attempt
+    pub base_backoff: Duration,
+    /// Upper bound of the backoff
+    pub max_backoff: Duration,
+}
+
+impl RetryConfig {
+    /// Exponential backoff with full jitter before retry number `attempt`
+    fn backoff(&self, attempt: u32) -> Duration {
+        let exponential = self
+            .base_backoff
+            .saturating_mul(1 << attempt.saturating_sub(1).min(16));
+        exponential
+            .min(self.max_backoff)
+            .mul_f64(rand::random::<f64>())
+    }
+}
 
 struct Shared {
-    batching_task: Notify,
+    retry: RetryConfig,
+    dead_letters: DeadLetters,
+    hedging: Option<Hedging>,
+    balancer: Balancer,
+    policy: Arc<dyn BatchPolicy>,
+    /// Batches cut by the queue and not done yet, retries included
+    batches_in_flight: AtomicUsize,
+    /// Set once the router gives up on the batches in flight to shut down
+    shutdown: watch::Sender<bool>,
+}
+
+impl Shared {
+    fn is_shutting_down(&self) -> bool {
+        *self.shutdown.borrow()
+    }
+
+    /// Resolves once the router shuts down
+    async fn shutting_down(&self) {
+        let _ = self.shutdown.subscribe().wait_for(|&down| down).await;
+    }
 }
 
 #[derive(Clone)]
 pub struct Processor {
     queue: Queue,
     shared: Arc<Shared>,
+    policy: Arc<dyn BatchPolicy>,
+    cache: Arc<ResponseCache>,
+    /// Deadline applied to requests that do not set `timeout_ms`
+    default_timeout: Duration,
 }
 
 impl Processor {
-    pub fn new(client: Client) -> Self {
+    pub fn backend_status(&self) -> Vec<BackendStatus> {
+        self.shared.balancer.status()
+    }
+
+    /// Drop every cached reply, returning how many there were
+    pub fn flush_cache(&self) -> usize {
+        self.cache.flush()
+    }
+
+    pub fn dead_letters(&self) -> &DeadLetters {
+        &self.shared.dead_letters
+    }
+
+    /// Queue the requests recovered from the write-ahead log again. Nobody waits for
+    /// their replies anymore, so they only go to the response cache.
+    pub fn recover(&self, recovered: Vec<Recovered>) {
+        let mut expired = 0;
+        for Recovered {
+            request,
+            deadline,
+            ticket,
+        } in recovered
+        {
+            if !self.restore(request, deadline, Some(ticket)) {
+                expired += 1;
+            }
+        }
+        if expired > 0 {
+            tracing::warn!("Dropped {} recovered requests past their deadline", expired);
+        }
+    }
+
+    /// Queue the requests of a shutdown snapshot again. Like recovered requests, their
+    /// replies only go to the response cache.
+    pub fn restore_snapshot(&self, pending: Vec<Pending>) {
+        let mut expired = 0;
+        for pending in pending {
+            let deadline = pending.deadline();
+            if !self.restore(pending.request, deadline, None) {
+                expired += 1;
+            }
+        }
+        if expired > 0 {
+            tracing::warn!("Dropped {} snapshot requests past their deadline", expired);
+        }
+    }
+
+    /// Queue a request nobody waits for, unless its deadline has passed
+    fn restore(
+        &self,
+        request: TextReplyRequest,
+        deadline: SystemTime,
+        ticket: Option<WalTicket>,
+    ) -> bool {
+        let Ok(timeout) = deadline.duration_since(SystemTime::now()) else {
+            if let Some(ticket) = ticket {
+                ticket.done();
+            }
+            return false;
+        };
+        let queue_time = Instant::now();
+        let cache_key = ResponseCache::key(&request);
+        let (mut entry, mut response_rx) =
+            QueueEntry::new(request, queue_time, queue_time + timeout);
+        entry.wal = ticket;
+        self.queue.restore(entry);
+
+        let cache = self.cache.clone();
+        tokio::spawn(async move {
+            match response_rx.recv().await {
+                Some(Ok(response)) => cache.insert(cache_key, response),
+                Some(Err(e)) => tracing::warn!("Restored request failed: {}", e),
+                None => {}
+            }
+        });
+        true
+    }
+
+    /// Wait until nothing is queued and no batch is in flight
+    pub async fn wait_idle(&self) {
+        while self.queue.depth() > 0 || self.has_batches_out() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    }
+
+    /// Whether a batch is on its way to a backend, at one, or waiting for a retry
+    fn has_batches_out(&self) -> bool {
+        // A batch holds its slot until the batching task has counted it
+        self.queue.batches_out() > 0 || self.shared.batches_in_flight.load(Ordering::SeqCst) > 0
+    }
+
+    /// Stop sending batches and take every request that has not got its response: the
+    /// queued ones, the ones in batches in flight, whose calls are cancelled, and the
+    /// ones waiting for a retry
+    pub async fn shutdown(&self) -> Vec<QueueEntry> {
+        self.queue.close();
+        self.shared.shutdown.send_replace(true);
+        // Cancelled batches hand their entries back to the queue
+        while self.has_batches_out() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        self.queue.drain().await
+    }
+
+    /// Process a dead-lettered request again, or `None` if there is no dead letter
+    /// with this id. If it fails again, it is dead-lettered under a new id. If it is not
+    /// admitted to the queue, the dead letter stays.
+    pub async fn replay_dead_letter(
+        &self,
+        id: u64,
+    ) -> Option<Result<TextReplyResponse, ProcessorError>> {
+        let letter = self.shared.dead_letters.take(id)?;
+        tracing::info!("Replaying dead letter {}", id);
+        let submitted = match self.submit(letter.request.clone()) {
+            Ok(submitted) => submitted,
+            Err(e) => {
+                self.shared.dead_letters.put_back(letter);
+                return Some(Err(e));
+            }
+        };
+        Some(submitted.wait(&self.cache).await)
+    }
+
+    pub fn new(balancer: Balancer, policy: Arc<dyn BatchPolicy>, config: ProcessorConfig) -> Self {
         let shared = Arc::new(Shared {
-            batching_task: Notify::new(),
+            retry: config.retry,
+            dead_letters: DeadLetters::new(config.dead_letter_capacity),
+            // A hedge needs a second backend to go to
+            hedging: config
+                .hedge
+                .filter(|_| balancer.backend_count() > 1)
+                .map(Hedging::new),
+            balancer,
+            policy: policy.clone(),
+            batches_in_flight: AtomicUsize::new(0),
+            shutdown: watch::channel(false).0,
         });
-        let queue = Queue::new();
+        let (queue, batches) = Queue::new(
+            policy.clone(),
+            config.queue,
+            config.max_concurrent_batches,
+            config.wal,
+        );
 
-        tokio::spawn(batching_task(queue.clone(), shared.clone(), client));
-        Self { queue, shared }
+        tokio::spawn(batching_task(queue.clone(), shared.clone(), batches));
+        Self {
+            queue,
+            shared,
+            policy,
+            cache: Arc::new(ResponseCache::new(config.cache)),
+            default_timeout: config.default_timeout,
+        }
     }
 
     #[instrument(skip_all)]
     pub async fn process_request(
         &self,
         request: TextReplyRequest,
-    ) -> Result<mpsc::UnboundedReceiver<TextReplyResponse>> {
-        let (response_tx, response_rx) = mpsc::unbounded_channel();
-        self.queue.append(QueueEntry {
-            request,
-            response_tx,
-        });
-        self.shared.batching_task.notify_one();
-        Ok(response_rx)
-    }
-}
-
-async fn batching_task(queue: Queue, shared: Arc<Shared>, mut client: Client) {
-    loop {
-        shared.batching_task.notified().await;
-        tokio::time::sleep(tokio::time::Duration::from_secs(2)).await; // Simulate batching.
-        while let Some((entries, batch, _)) = queue.next_batch().await {
-            let (batch_id, _) = (batch.id.clone(), batch.size.clone());
-            let batch_response = client.generate_reply(batch).await.unwrap();
-            let all_responses = batch_response
-                .responses
-                .iter()
-                .map(|r| r.message.clone())
-                .collect::<Vec<String>>();
-
-            let time = batch_response.elapsed;
-
-            for (response, (_, entry)) in
-                std::iter::zip(batch_response.responses.into_iter(), entries.into_iter())
-            {
-                let response = TextReplyResponse {
-                    message: response.message,
-                    batch_id: batch_id as u32,
-                    request_id: response.request_id as u32,
-                    batch_size: all_responses.len() as u32,
-                    processing_time: time,
-                    other_responses: all_responses.clone(),
-                };
-                let _ = entry.response_tx.send(response).map_err(|e| {
-                    tracing::error!("Error: {:?}", e);
-                });
+    ) -> Result<TextReplyResponse, ProcessorError> {
+        self.submit(request)?.wait(&self.cache).await
+    }
+
+    /// Process several requests through the same queue, returning their results in
+    /// the order of `requests`. All of them are queued before waiting for any, so they
+    /// may share batches with each other and with other callers.
+    #[instrument(skip_all, fields(requests = requests.len()))]
+    pub async fn process_requests(
+        &self,
+        requests: Vec<TextReplyRequest>,
+    ) -> Vec<Result<TextReplyResponse, ProcessorError>> {
+        let submitted = requests
+            .into_iter()
+            .map(|request| self.submit(request))
+            .collect::<Vec<_>>();
+        let mut results = Vec::with_capacity(submitted.len());
+        for submitted in submitted {
+            results.push(match submitted {
+                Ok(submitted) => submitted.wait(&self.cache).await,
+                Err(e) => Err(e),
+            });
+        }
+        results
+    }
+
+    /// Answer a request from the cache or append it to the queue
+    fn submit(&self, request: TextReplyRequest) -> Result<Submitted, ProcessorError> {
+        let cache_key = ResponseCache::key(&request);
+        if let Some(mut response) = self.cache.get(&cache_key) {
+            response.from_cache = true;
+            return Ok(Submitted::Cached(response));
+        }
+
+        self.policy.admit(&request)?;
+        if !self.shared.balancer.any_available() {
+            // Fail fast instead of queueing requests that no backend would take
+            return Err(ProcessorError::Unavailable(
+                "Circuit breaker is open for every backend".to_string(),
+            ));
+        }
+
+        let queue_time = Instant::now();
+        let timeout = request
+            .timeout_ms
+            .map(Duration::from_millis)
+            .unwrap_or(self.default_timeout);
+        let deadline = queue_time + timeout;
+
+        let (entry, response_rx) = QueueEntry::new(request, queue_time, deadline);
+        self.queue.append(entry)?;
+        self.policy.record_arrival(queue_time);
+        Ok(Submitted::Queued {
+            cache_key,
+            deadline,
+            response_rx,
+        })
+    }
+}
+
+/// A request answered from the cache or waiting in the queue
+enum Submitted {
+    Cached(TextReplyResponse),
+    Queued {
+        cache_key: TextReplyRequest,
+        deadline: Instant,
+        response_rx: ResponseRx,
+    },
+}
+
+impl Submitted {
+    /// Wait for the response, caching it
+    async fn wait(self, cache: &ResponseCache) -> Result<TextReplyResponse, ProcessorError> {
+        let (cache_key, deadline, mut response_rx) = match self {
+            Submitted::Cached(response) => return Ok(response),
+            Submitted::Queued {
+                cache_key,
+                deadline,
+                response_rx,
+            } => (cache_key, deadline, response_rx),
+        };
+        match tokio::time::timeout_at(deadline, response_rx.recv()).await {
+            Ok(Some(Ok(response))) => {
+                cache.insert(cache_key, response.clone());
+                Ok(response)
+            }
+            Ok(Some(Err(e))) => Err(e),
+            Ok(None) => Err(ProcessorError::Dropped),
+            Err(_) => Err(ProcessorError::Timeout),
+        }
+    }
+}
+
+async fn batching_task(
+    queue: Queue,
+    shared: Arc<Shared>,
+    mut batches: mpsc::UnboundedReceiver<ReadyBatch>,
+) {
+    while let Some(((entries, batch, span), permit)) = batches.recv().await {
+        shared.batches_in_flight.fetch_add(1, Ordering::SeqCst);
+        tokio::spawn(
+            send_batch(queue.clone(), shared.clone(), entries, batch, permit).instrument(span),
+        );
+    }
+}
+
+/// Send a batch to a backend and deliver its responses.
+/// The batch occupies an in-flight slot until `permit` is dropped.
+async fn send_batch(
+    queue: Queue,
+    shared: Arc<Shared>,
+    entries: HashMap<u64, QueueEntry>,
+    batch: ClientBatch,
+    permit: OwnedSemaphorePermit,
+) {
+    let backend = shared.balancer.pick();
+    let failures = send_parts(&shared, &backend, entries, batch).await;
+    // Free the backend and the slot before backing off
+    drop(backend);
+    drop(permit);
+    for (error, entries) in failures {
+        if shared.is_shutting_down() {
+            // Kept for the shutdown snapshot
+            queue.requeue(entries.into_iter().collect());
+            continue;
+        }
+        retry_or_fail(error, entries, &queue, &shared).await;
+    }
+    shared.batches_in_flight.fetch_sub(1, Ordering::SeqCst);
+}
+
+/// Entries of a batch, or part of one, that failed with the error
+type Failure = (ClientError, HashMap<u64, QueueEntry>);
+
+/// Send a batch to `backend` and deliver its responses. Batches larger than the cap
+/// of the backend, or rejected by it as too large, are bisected and the halves sent
+/// one after the other, so that splitting does not add to the load of the backend.
+/// Returns the parts that failed for other reasons.
+fn send_parts<'a>(
+    shared: &'a Shared,
+    backend: &'a Backend,
+    entries: HashMap<u64, QueueEntry>,
+    batch: ClientBatch,
+) -> Pin<Box<dyn Future<Output = Vec<Failure>> + Send + 'a>> {
+    Box::pin(async move {
+        let bisect = |mut entries: HashMap<u64, QueueEntry>, batch: ClientBatch| async move {
+            let (first, second) = batch.split();
+            let second_entries = second
+                .request_ids()
+                .filter_map(|id| entries.remove_entry(&id))
+                .collect();
+            let mut failures = send_parts(shared, backend, entries, first).await;
+            failures.extend(send_parts(shared, backend, second_entries, second).await);
+            failures
+        };
+        if backend
+            .batch_size_cap()
+            .is_some_and(|cap| batch.len() > cap)
+        {
+            return bisect(entries, batch).await;
+        }
+
+        // Nobody is waiting for the reply once the most patient caller has given up.
+        // Halves go out one after the other, so each call gets the time that is left.
+        let batch = match entries.values().map(|entry| entry.deadline).max() {
+            Some(deadline) => {
+                batch.with_timeout(deadline.saturating_duration_since(Instant::now()))
+            }
+            None => batch,
+        };
+        let batch_id = batch.id;
+        let batch_size = batch.len();
+        let start_time = Instant::now();
+        let (result, hedge, latency) = tokio::select! {
+            outcome = call(shared, backend, &batch) => outcome,
+            _ = shared.shutting_down() => {
+                let error = Status::cancelled("Router is shutting down");
+                return vec![(error.into(), entries)];
+            }
+        };
+        // Hold on to the hedge backend until the outcome is recorded
+        let backend = hedge.as_deref().unwrap_or(backend);
+        let batch_response = match result {
+            Ok(batch_response) => {
+                if let Some(hedging) = &shared.hedging {
+                    hedging.record(start_time.elapsed());
+                }
+                shared.balancer.record_success(backend, latency);
+                backend.record_accepted(batch_size);
+                shared.policy.record_batch(entries.len(), latency);
+                batch_response
+            }
+            Err(e) if batch_size > 1 && backend.rejected_as_too_large(&e, batch_size) => {
+                tracing::warn!(
+                    "Batch {} of {} requests too large for {}: {}",
+                    batch_id,
+                    batch_size,
+                    backend.address,
+                    e
+                );
+                metrics::counter!("router_batch_splits_total").increment(1);
+                backend.learn_batch_size_cap(batch_size);
+                return bisect(entries, batch).await;
+            }
+            Err(e) => {
+                // A call rejected by the circuit breaker says nothing new about the backend
+                if !matches!(e, ClientError::CircuitOpen) {
+                    shared.balancer.record_failure(backend);
+                }
+                tracing::error!("Batch {} failed on {}: {}", batch_id, backend.address, e);
+                metrics::counter!(
+                    "router_batch_failures_total",
+                    "retryable" => e.is_retryable().to_string()
+                )
+                .increment(1);
+                return vec![(e, entries)];
+            }
+        };
+        deliver_responses(batch_id, entries, batch_response);
+        Vec::new()
+    })
+}
+
+/// Send a batch to `backend`. With hedging enabled, a batch still unanswered after
+/// the hedging delay also goes to a second backend. The first successful answer wins
+/// and the other call is cancelled by dropping it.
+///
+/// Returns the result, the hedge backend if the result came from it, and the time
+/// the call that produced the result took.
+async fn call(
+    shared: &Shared,
+    backend: &Backend,
+    batch: &ClientBatch,
+) -> (
+    Result<ReplyResponse, ClientError>,
+    Option<BackendGuard>,
+    Duration,
+) {
+    // A batch rejected for its size is bisected, which is no failure of the backend
+    let size = batch.len();
+    let mut client = backend.client.clone();
+    let start_time = Instant::now();
+    let primary = client.generate_reply_with(batch, |e| backend.rejected_as_too_large(e, size));
+    tokio::pin!(primary);
+
+    let Some((hedging, delay)) = shared
+        .hedging
+        .as_ref()
+        .and_then(|hedging| Some((hedging, hedging.delay()?)))
+    else {
+        return (primary.await, None, start_time.elapsed());
+    };
+    let result = tokio::select! {
+        result = &mut primary => Some(result),
+        _ = tokio::time::sleep(delay) => None,
+    };
+    if let Some(result) = result {
+        return (result, None, start_time.elapsed());
+    }
+
+    let Some(hedge) = shared
+        .balancer
+        .pick_hedge(backend)
+        .filter(|_| hedging.try_acquire())
+    else {
+        return (primary.await, None, start_time.elapsed());
+    };
+    tracing::debug!(
+        "Hedging batch {} on {} after {:?}",
+        batch.id,
+        hedge.address,
+        delay
+    );
+    metrics::counter!("router_hedged_batches_total").increment(1);
+    let mut hedge_client = hedge.client.clone();
+    let hedge_start = Instant::now();
+    // A failed call leaves the race to the other one
+    let (result, from_hedge) = {
+        let hedged =
+            hedge_client.generate_reply_with(batch, |e| hedge.rejected_as_too_large(e, size));
+        tokio::pin!(hedged);
+        tokio::select! {
+            result = &mut primary => match result {
+                Ok(response) => (Ok(response), false),
+                Err(e) => {
+                    tracing::warn!("Batch {} failed on {} while hedged: {}", batch.id, backend.address, e);
+                    (hedged.await, true)
+                }
+            },
+            result = &mut hedged => match result {
+                Ok(response) => (Ok(response), true),
+                Err(e) => {
+                    tracing::warn!("Hedge of batch {} failed on {}: {}", batch.id, hedge.address, e);
+                    (primary.await, false)
+                }
+            },
+        }
+    };
+    if from_hedge {
+        if result.is_ok() {
+            metrics::counter!("router_hedge_wins_total").increment(1);
+        }
+        (result, Some(hedge), hedge_start.elapsed())
+    } else {
+        (result, None, start_time.elapsed())
+    }
+}
+
+/// Requeue the entries of a failed batch that have retries left, fail all others.
+/// Requests failing for a reason other than their deadline are dead-lettered.
+async fn retry_or_fail(
+    error: ClientError,
+    entries: HashMap<u64, QueueEntry>,
+    queue: &Queue,
+    shared: &Shared,
+) {
+    if !error.is_retryable() {
+        for mut entry in entries.into_values() {
+            let err = if error.is_timeout() {
+                ProcessorError::Timeout
+            } else {
+                entry.attempts += 1;
+                entry.failures.push(FailedAttempt::now(error.to_string()));
+                shared.dead_letters.record(&entry);
+                ProcessorError::Backend(error.to_string())
+            };
+            entry.respond(Err(err));
+        }
+        return;
+    }
+
+    let mut retries = Vec::with_capacity(entries.len());
+    let mut attempt = 0;
+    for (id, mut entry) in entries {
+        entry.attempts += 1;
+        entry.failures.push(FailedAttempt::now(error.to_string()));
+        if entry.attempts > shared.retry.max_retries {
+            shared.dead_letters.record(&entry);
+            entry.respond(Err(ProcessorError::Unavailable(error.to_string())));
+            continue;
+        }
+        attempt = attempt.max(entry.attempts);
+        retries.push((id, entry));
+    }
+    if retries.is_empty() {
+        return;
+    }
+
+    let backoff = shared.retry.backoff(attempt);
+    tracing::warn!(
+        "Retrying {} requests in {:?} (attempt {})",
+        retries.len(),
+        backoff,
+        attempt
+    );
+    metrics::counter!("router_retried_requests_total").increment(retries.len() as u64);
+    tokio::select! {
+        _ = tokio::time::sleep(backoff) => {}
+        // The queue keeps them for the shutdown snapshot
+        _ = shared.shutting_down() => {}
+    }
+
+    // Expired entries are evicted by the queue before the next batch is cut
+    queue.requeue(retries);
+}
+
+/// Hand every response to the entry with the same request id.
+/// Entries the backend did not answer get [`ProcessorError::MissingResponse`].
+fn deliver_responses(
+    batch_id: u64,
+    mut entries: HashMap<u64, QueueEntry>,
+    batch_response: ReplyResponse,
+) {
+    let batch_size = entries.len() as u32;
+    let all_responses = batch_response
+        .responses
+        .iter()
+        .filter(|r| r.error.is_none())
+        .map(|r| r.message.clone())
+        .collect::<Vec<String>>();
+
+    let time = batch_response.elapsed;
+    let mut delivered = HashSet::with_capacity(entries.len());
+
+    for response in batch_response.responses {
+        let Some(entry) = entries.remove(&response.request_id) else {
+            let reason = if delivered.contains(&response.request_id) {
+                "duplicate"
+            } else {
+                "unknown"
+            };
+            tracing::warn!(
+                "Batch {}: {} response for request {}",
+                batch_id,
+                reason,
+                response.request_id
+            );
+            metrics::counter!("router_unexpected_responses_total", "reason" => reason).increment(1);
+            continue;
+        };
+        delivered.insert(response.request_id);
+
+        if let Some(error) = response.error {
+            entry.respond(Err(ProcessorError::Rejected {
+                code: Code::from_i32(error.code),
+                message: error.message,
+            }));
+            continue;
+        }
+
+        let response = TextReplyResponse {
+            message: response.message,
+            batch_id: batch_id as u32,
+            request_id: response.request_id as u32,
+            batch_size,
+            processing_time: time,
+            other_responses: all_responses.clone(),
+            from_cache: false,
+        };
+        entry.respond(Ok(response));
+    }
+
+    for (id, entry) in entries {
+        tracing::error!("Batch {}: no response for request {}", batch_id, id);
+        metrics::counter!("router_missing_responses_total").increment(1);
+        entry.respond(Err(ProcessorError::MissingResponse));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::balancer::LoadBalancing;
+    use crate::health::OutlierConfig;
+    use crate::queue::tests::{config, policy, request};
+    use crate::queue::{QueueEntry, ResponseRx};
+    use rand::{rngs::StdRng, seq::SliceRandom, SeedableRng};
+    use reply_client::{RequestError, Response};
+
+    fn entry(message: &str) -> (QueueEntry, ResponseRx) {
+        let now = Instant::now();
+        QueueEntry::new(request(message), now, now + Duration::from_secs(1))
+    }
+
+    /// A processor for a backend that is never reached
+    fn processor() -> Processor {
+        let client = reply_client::Client::new("http://127.0.0.1:1".parse().unwrap());
+        let outlier = OutlierConfig {
+            consecutive_failures: 5,
+            max_latency: Duration::from_secs(1),
+            base_ejection: Duration::from_secs(30),
+            readmission: Duration::from_secs(10),
+        };
+        let balancer = Balancer::new(
+            vec![Backend::new("127.0.0.1:1".to_string(), client)],
+            LoadBalancing::RoundRobin,
+            outlier,
+        );
+        let config = ProcessorConfig {
+            default_timeout: Duration::from_secs(1),
+            max_concurrent_batches: 1,
+            retry: RetryConfig {
+                max_retries: 0,
+                base_backoff: Duration::from_millis(10),
+                max_backoff: Duration::from_millis(10),
+            },
+            queue: config(),
+            cache: CacheConfig {
+                capacity: 0,
+                ttl: Duration::ZERO,
+            },
+            dead_letter_capacity: 0,
+            hedge: None,
+            wal: None,
+        };
+        Processor::new(balancer, policy(8, Duration::ZERO), config)
+    }
+
+    fn response(request_id: u64) -> Response {
+        Response {
+            request_id,
+            message: format!("reply to {request_id}"),
+            error: None,
+        }
+    }
+
+    #[test]
+    fn shuffled_responses_reach_their_requests() {
+        let mut rng = StdRng::seed_from_u64(42);
+        for _ in 0..50 {
+            let mut entries = HashMap::new();
+            let mut receivers = Vec::new();
+            for id in 1..=16 {
+                let (entry, response_rx) = entry(&format!("request {id}"));
+                entries.insert(id, entry);
+                receivers.push((id, response_rx));
+            }
+
+            let mut responses = (1..=16).map(response).collect::<Vec<_>>();
+            responses.shuffle(&mut rng);
+            deliver_responses(
+                1,
+                entries,
+                ReplyResponse {
+                    responses,
+                    elapsed: 0.0,
+                },
+            );
+
+            for (id, mut response_rx) in receivers {
+                let reply = response_rx.try_recv().unwrap().unwrap();
+                assert_eq!(reply.message, format!("reply to {id}"));
+                assert_eq!(reply.request_id as u64, id);
+                assert_eq!(reply.batch_size, 16);
             }
         }
     }
+
+    #[test]
+    fn unanswered_requests_get_an_error() {
+        let (first, mut first_rx) = entry("first");
+        let (second, mut second_rx) = entry("second");
+        let entries = HashMap::from([(1, first), (2, second)]);
+
+        // A duplicate and an unknown id must not be handed to anybody
+        let responses = vec![response(1), response(1), response(99)];
+        deliver_responses(
+            1,
+            entries,
+            ReplyResponse {
+                responses,
+                elapsed: 0.0,
+            },
+        );
+
+        assert_eq!(first_rx.try_recv().unwrap().unwrap().message, "reply to 1");
+        assert!(first_rx.try_recv().is_err());
+        assert!(matches!(
+            second_rx.try_recv().unwrap(),
+            Err(ProcessorError::MissingResponse)
+        ));
+    }
+
+    #[test]
+    fn failed_item_does_not_fail_the_batch() {
+        let (ok, mut ok_rx) = entry("fine");
+        let (failed, mut failed_rx) = entry("");
+        let entries = HashMap::from([(1, ok), (2, failed)]);
+
+        let responses = vec![
+            response(1),
+            Response {
+                request_id: 2,
+                message: String::new(),
+                error: Some(RequestError {
+                    code: Code::InvalidArgument as i32,
+                    message: "Message is empty".to_string(),
+                }),
+            },
+        ];
+        deliver_responses(
+            1,
+            entries,
+            ReplyResponse {
+                responses,
+                elapsed: 0.0,
+            },
+        );
+
+        let reply = ok_rx.try_recv().unwrap().unwrap();
+        assert_eq!(reply.other_responses, vec!["reply to 1".to_string()]);
+        assert!(matches!(
+            failed_rx.try_recv().unwrap(),
+            Err(ProcessorError::Rejected {
+                code: Code::InvalidArgument,
+                ..
+            })
+        ));
+    }
+
+    #[tokio::test]
+    async fn abandoned_requests_leave_the_processor_idle() {
+        let processor = processor();
+        let now = Instant::now();
+        let (entry, response_rx) = QueueEntry::new(request("a"), now, now + Duration::from_secs(1));
+        drop(response_rx);
+        processor.queue.append(entry).unwrap();
+        tokio::time::timeout(Duration::from_secs(1), processor.wait_idle())
+            .await
+            .expect("the processor did not become idle");
+    }
 }
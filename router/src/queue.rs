@@ -1,132 +1,980 @@
-use crate::{TextReplyRequest, TextReplyResponse};
+use crate::dead_letter::FailedAttempt;
+use crate::policy::{BatchPolicy, BatchUsage, BudgetUnit};
+use crate::processor::ProcessorError;
+use crate::wal::{Wal, WalTicket};
+use crate::{Priority, TextReplyRequest, TextReplyResponse};
 use reply_client::{ClientBatch, HttpRequest};
-use std::collections::{HashMap, VecDeque};
-use tokio::sync::{mpsc, oneshot};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tokio::sync::{mpsc, oneshot, OwnedSemaphorePermit, Semaphore};
+use tokio::time::Instant;
 use tracing::{instrument, Span};
 
 #[derive(Debug, Clone)]
-pub(crate) struct QueueEntry {
+pub struct QueueEntry {
     pub request: TextReplyRequest,
     /// Response sender to communicate between the Infer struct and the batching_task
-    pub response_tx: mpsc::UnboundedSender<TextReplyResponse>,
+    pub response_tx: mpsc::UnboundedSender<Result<TextReplyResponse, ProcessorError>>,
+    /// Instant when this entry was queued
+    pub queue_time: Instant,
+    /// Instant after which the caller no longer waits for a response
+    pub deadline: Instant,
+    /// Number of times this entry was already sent to the backend without success
+    pub attempts: u32,
+    /// Identical requests coalesced into this one. They get the same response.
+    pub followers: Vec<QueueEntry>,
+    /// Errors of earlier attempts, oldest first
+    pub failures: Vec<FailedAttempt>,
+    /// Record of the entry in the write-ahead log, if there is one
+    pub wal: Option<WalTicket>,
+}
+
+/// Receiver of the response to a [`QueueEntry`]
+pub type ResponseRx = mpsc::UnboundedReceiver<Result<TextReplyResponse, ProcessorError>>;
+
+impl QueueEntry {
+    /// A new entry that was not sent yet, and the receiver of its response
+    pub fn new(
+        request: TextReplyRequest,
+        queue_time: Instant,
+        deadline: Instant,
+    ) -> (Self, ResponseRx) {
+        let (response_tx, response_rx) = mpsc::unbounded_channel();
+        let entry = Self {
+            request,
+            response_tx,
+            queue_time,
+            deadline,
+            attempts: 0,
+            followers: Vec::new(),
+            failures: Vec::new(),
+            wal: None,
+        };
+        (entry, response_rx)
+    }
+
+    pub fn tenant(&self) -> &str {
+        self.request.tenant.as_deref().unwrap_or(DEFAULT_TENANT)
+    }
+
+    /// Send `result` to the caller and to the callers of all followers
+    pub fn respond(&self, result: Result<TextReplyResponse, ProcessorError>) {
+        for follower in &self.followers {
+            follower.respond(result.clone());
+        }
+        let _ = self.response_tx.send(result);
+        if let Some(ticket) = &self.wal {
+            ticket.done();
+        }
+    }
+
+    /// Whether nobody waits for the response anymore
+    fn is_abandoned(&self) -> bool {
+        self.response_tx.is_closed() && self.followers.iter().all(QueueEntry::is_abandoned)
+    }
+
+    /// Coalesce `other` into this entry. The entry with the later deadline leads, so
+    /// that the callers of the followers have given up by the time it expires. The
+    /// leader takes over the attempts and failures of both, so that a newer duplicate
+    /// does not reset the retry budget.
+    fn absorb(&mut self, mut other: QueueEntry) {
+        if other.deadline > self.deadline {
+            std::mem::swap(self, &mut other);
+        }
+        self.attempts = self.attempts.max(other.attempts);
+        self.failures.append(&mut other.failures);
+        self.failures.sort_by_key(|failure| failure.at_ms);
+        self.followers.append(&mut other.followers);
+        self.followers.push(other);
+    }
+}
+
+/// Splits the queue into sub-queues by message length so that a batch only holds
+/// requests of similar length
+#[derive(Debug, Clone)]
+pub struct Bucketing {
+    /// Largest message length of every bucket but the last, in ascending order.
+    /// Without boundaries all requests share one bucket.
+    pub boundaries: Vec<usize>,
+    /// Unit of `boundaries`
+    pub unit: BudgetUnit,
+    /// Requests waiting longer than this are served before any other bucket
+    pub starvation: Duration,
+}
+
+impl Bucketing {
+    fn buckets(&self) -> usize {
+        self.boundaries.len() + 1
+    }
+
+    fn bucket(&self, request: &TextReplyRequest) -> usize {
+        let length = self.unit.measure(&request.message);
+        self.boundaries
+            .partition_point(|&boundary| boundary < length)
+    }
+}
+
+/// How the queue orders and groups waiting requests
+#[derive(Debug, Clone)]
+pub struct QueueConfig {
+    /// Maximum number of requests waiting in the queue
+    pub max_depth: usize,
+    pub bucketing: Bucketing,
+    /// Waiting time after which a request counts as one priority class higher.
+    /// Zero disables aging.
+    pub priority_aging: Duration,
+    pub tenant_weights: TenantWeights,
+    /// Send identical requests, same message and parameters, that wait at the same
+    /// time only once
+    pub dedup: bool,
 }
 
 #[derive(Debug)]
 enum QueueCommand {
     Append(Box<QueueEntry>, Span),
-    NextBatch {
-        response_sender: oneshot::Sender<Option<NextBatch>>,
-        span: Span,
-    },
+    Requeue(Vec<(u64, QueueEntry)>, Span),
+    Drain(oneshot::Sender<Vec<QueueEntry>>),
+    Close,
 }
 
 #[derive(Debug, Clone)]
-pub(crate) struct Queue {
+pub struct Queue {
     queue_sender: mpsc::UnboundedSender<QueueCommand>,
+    /// Number of entries appended and not yet batched or evicted
+    depth: Arc<AtomicUsize>,
+    max_depth: usize,
+    wal: Option<Arc<Wal>>,
+    /// In-flight slots, one per batch cut and not done yet
+    slots: Arc<Semaphore>,
+    max_concurrent_batches: usize,
 }
 
-type NextBatch = (HashMap<u32, QueueEntry>, ClientBatch, Span);
+pub type NextBatch = (HashMap<u64, QueueEntry>, ClientBatch, Span);
+
+/// A batch cut by the queue. It occupies an in-flight slot until the permit is dropped.
+pub type ReadyBatch = (NextBatch, OwnedSemaphorePermit);
+
+/// Outcome of [`QueueState::next_batch`]
+#[derive(Debug)]
+pub(crate) enum BatchStatus {
+    /// A batch is ready to be sent to the backend
+    Ready(NextBatch),
+    /// Entries are queued, but the policy does not want to cut a batch yet.
+    /// Ask again at the given instant at the latest.
+    Pending(Instant),
+    /// Nothing to batch
+    Empty,
+}
 
 impl Queue {
-    pub fn new() -> Self {
+    /// Start the queue. Batches are cut as soon as the policy allows it and one of the
+    /// `max_concurrent_batches` in-flight slots is free, and come out of the receiver.
+    pub fn new(
+        policy: Arc<dyn BatchPolicy>,
+        config: QueueConfig,
+        max_concurrent_batches: usize,
+        wal: Option<Arc<Wal>>,
+    ) -> (Self, mpsc::UnboundedReceiver<ReadyBatch>) {
         let (queue_sender, queue_receiver) = mpsc::unbounded_channel();
-        tokio::spawn(queue_task(queue_receiver));
-        Self { queue_sender }
+        let (batch_sender, batch_receiver) = mpsc::unbounded_channel();
+        let depth = Arc::new(AtomicUsize::new(0));
+        let max_depth = config.max_depth;
+        let state = QueueState::new(policy, config, depth.clone());
+        let slots = Arc::new(Semaphore::new(max_concurrent_batches));
+        tokio::spawn(queue_task(
+            queue_receiver,
+            batch_sender,
+            state,
+            slots.clone(),
+        ));
+        let queue = Self {
+            queue_sender,
+            depth,
+            max_depth,
+            wal,
+            slots,
+            max_concurrent_batches,
+        };
+        (queue, batch_receiver)
     }
 
+    /// Append an entry, or fail with [`ProcessorError::Overloaded`] if `max_depth`
+    /// entries are already waiting. The entry is logged first if there is a
+    /// write-ahead log.
     #[instrument(skip_all)]
-    pub fn append(&self, entry: QueueEntry) {
+    pub fn append(&self, mut entry: QueueEntry) -> Result<(), ProcessorError> {
+        // Reserve a slot before sending so that concurrent appends cannot overshoot
+        self.depth
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |depth| {
+                (depth < self.max_depth).then_some(depth + 1)
+            })
+            .map_err(|_| ProcessorError::Overloaded)?;
+
+        if let Some(wal) = &self.wal {
+            let deadline =
+                SystemTime::now() + entry.deadline.saturating_duration_since(Instant::now());
+            match wal.append(&entry.request, deadline) {
+                Ok(ticket) => entry.wal = Some(ticket),
+                Err(e) => {
+                    self.depth.fetch_sub(1, Ordering::SeqCst);
+                    tracing::error!("Failed to write to the write-ahead log: {}", e);
+                    return Err(ProcessorError::Unavailable(
+                        "Write-ahead log is not writable".to_string(),
+                    ));
+                }
+            }
+        }
+
         let command = QueueCommand::Append(Box::new(entry), Span::current());
         self.queue_sender.send(command).unwrap();
+        Ok(())
     }
 
+    /// Append an entry recovered after a restart. It is not subject to `max_depth`,
+    /// and logged unless it comes from the write-ahead log.
+    pub fn restore(&self, mut entry: QueueEntry) {
+        if let (Some(wal), None) = (&self.wal, &entry.wal) {
+            let deadline =
+                SystemTime::now() + entry.deadline.saturating_duration_since(Instant::now());
+            match wal.append(&entry.request, deadline) {
+                Ok(ticket) => entry.wal = Some(ticket),
+                Err(e) => tracing::error!("Failed to write to the write-ahead log: {}", e),
+            }
+        }
+        self.depth.fetch_add(1, Ordering::SeqCst);
+        let command = QueueCommand::Append(Box::new(entry), Span::current());
+        self.queue_sender.send(command).unwrap();
+    }
+
+    /// Put entries of a failed batch back in front of the queue. They keep their id,
+    /// queue time and deadline and are not subject to `max_depth`.
     #[instrument(skip_all)]
-    pub async fn next_batch(&self) -> Option<NextBatch> {
-        let (response_sender, response_receiver) = oneshot::channel();
-        let command = QueueCommand::NextBatch {
-            response_sender,
-            span: Span::current(),
-        };
+    pub fn requeue(&self, entries: Vec<(u64, QueueEntry)>) {
+        self.depth.fetch_add(entries.len(), Ordering::SeqCst);
+        let command = QueueCommand::Requeue(entries, Span::current());
         self.queue_sender.send(command).unwrap();
-        response_receiver.await.unwrap()
     }
+
+    /// Number of entries waiting in the queue
+    pub fn depth(&self) -> usize {
+        self.depth.load(Ordering::SeqCst)
+    }
+
+    /// Number of batches cut that still hold their in-flight slot
+    pub fn batches_out(&self) -> usize {
+        self.max_concurrent_batches - self.slots.available_permits()
+    }
+
+    /// Stop cutting batches. Entries appended or requeued afterwards stay in the queue
+    /// until it is drained.
+    pub fn close(&self) {
+        self.queue_sender.send(QueueCommand::Close).unwrap();
+    }
+
+    /// Remove and return every waiting entry, oldest first
+    pub async fn drain(&self) -> Vec<QueueEntry> {
+        let (sender, receiver) = oneshot::channel();
+        self.queue_sender.send(QueueCommand::Drain(sender)).unwrap();
+        receiver.await.unwrap()
+    }
+}
+
+/// Minimum time between two sweeps of the whole queue for dead entries. In between,
+/// only the entries of the batch being cut are checked.
+const EVICTION_INTERVAL: Duration = Duration::from_millis(100);
+/// Minimum time between two updates of the per-tenant depth metrics
+const TENANT_DEPTH_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Tenant of requests that do not name one
+pub const DEFAULT_TENANT: &str = "default";
+/// Tenant label of the depth metrics of all tenants without a configured weight
+const OTHER_TENANTS: &str = "other";
+
+/// Entries of one priority class within a bucket. Every tenant has its own sub-queue,
+/// oldest first, and tenants take turns in weighted round-robin order.
+#[derive(Debug, Clone, Default)]
+struct Lane {
+    tenants: HashMap<Arc<str>, VecDeque<(u64, QueueEntry)>>,
+    /// Tenants with queued entries, the one whose turn it is in front
+    turns: VecDeque<Arc<str>>,
+    /// Entries the tenant in front may still take in its current turn, 0 before the
+    /// turn has started
+    quota: usize,
+    /// Number of queued entries over all tenants
+    len: usize,
+}
+
+impl Lane {
+    fn len(&self) -> usize {
+        self.len
+    }
+
+    fn oldest(&self) -> Option<Instant> {
+        self.tenants
+            .values()
+            .filter_map(|entries| entries.front())
+            .map(|(_, entry)| entry.queue_time)
+            .min()
+    }
+
+    fn sub_queue(&mut self, tenant: &str) -> &mut VecDeque<(u64, QueueEntry)> {
+        if !self.tenants.contains_key(tenant) {
+            let tenant: Arc<str> = Arc::from(tenant);
+            self.turns.push_back(tenant.clone());
+            self.tenants.insert(tenant, VecDeque::new());
+        }
+        self.tenants.get_mut(tenant).unwrap()
+    }
+
+    fn push_back(&mut self, entry: (u64, QueueEntry)) {
+        self.sub_queue(entry.1.tenant()).push_back(entry);
+        self.len += 1;
+    }
+
+    fn push_front(&mut self, entry: (u64, QueueEntry)) {
+        self.sub_queue(entry.1.tenant()).push_front(entry);
+        self.len += 1;
+    }
+
+    fn retain(&mut self, mut f: impl FnMut(&(u64, QueueEntry)) -> bool) {
+        for entries in self.tenants.values_mut() {
+            entries.retain(&mut f);
+        }
+        self.len = self.tenants.values().map(VecDeque::len).sum();
+        self.forget_idle();
+    }
+
+    /// Remove and return the entries matching `f`
+    fn extract(&mut self, mut f: impl FnMut(&QueueEntry) -> bool) -> Vec<(u64, QueueEntry)> {
+        let mut extracted = Vec::new();
+        for entries in self.tenants.values_mut() {
+            let mut rest = VecDeque::with_capacity(entries.len());
+            for entry in entries.drain(..) {
+                if f(&entry.1) {
+                    extracted.push(entry);
+                } else {
+                    rest.push_back(entry);
+                }
+            }
+            *entries = rest;
+        }
+        self.len -= extracted.len();
+        self.forget_idle();
+        extracted
+    }
+
+    /// Drop tenants without queued entries
+    fn forget_idle(&mut self) {
+        self.tenants.retain(|_, entries| !entries.is_empty());
+        let front = self.turns.front().cloned();
+        self.turns
+            .retain(|tenant| self.tenants.contains_key(tenant));
+        if self.turns.front() != front.as_ref() {
+            self.quota = 0;
+        }
+    }
+
+    /// Entries in the order the round-robin hands them out
+    fn schedule<'a>(&'a self, weights: &'a TenantWeights) -> Schedule<'a> {
+        Schedule {
+            lane: self,
+            weights,
+            turn: 0,
+            quota: self.quota,
+            taken: vec![0; self.turns.len()],
+            remaining: self.len,
+        }
+    }
+
+    /// Remove the next `n` entries of the schedule, in a single pass over each
+    /// tenant's sub-queue
+    fn take(&mut self, n: usize, weights: &TenantWeights) -> Vec<(u64, QueueEntry)> {
+        let mut schedule = self.schedule(weights);
+        for _ in 0..n {
+            schedule.next();
+        }
+        let Schedule {
+            turn, quota, taken, ..
+        } = schedule;
+
+        let mut entries = Vec::with_capacity(n);
+        for (tenant, count) in self.turns.iter().zip(taken) {
+            entries.extend(self.tenants.get_mut(tenant).unwrap().drain(..count));
+        }
+        self.len -= entries.len();
+        // The tenant whose turn it is goes in front
+        self.turns.rotate_left(turn);
+        self.quota = quota;
+        self.forget_idle();
+        entries
+    }
+}
+
+/// Walks a lane in weighted round-robin order without changing it
+struct Schedule<'a> {
+    lane: &'a Lane,
+    weights: &'a TenantWeights,
+    /// Index in `lane.turns` of the tenant whose turn it is
+    turn: usize,
+    quota: usize,
+    /// Entries handed out so far, by index in `lane.turns`
+    taken: Vec<usize>,
+    /// Entries not handed out yet
+    remaining: usize,
 }
 
-struct QueueState {
-    entries: VecDeque<(u64, QueueEntry)>,
+impl<'a> Iterator for Schedule<'a> {
+    type Item = &'a (u64, QueueEntry);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.remaining == 0 {
+            return None;
+        }
+        let turns = &self.lane.turns;
+        loop {
+            let tenant = &turns[self.turn];
+            let taken = &mut self.taken[self.turn];
+            let Some(entry) = self.lane.tenants[tenant].get(*taken) else {
+                // Nothing left, the tenant gives up the rest of its turn
+                self.turn = (self.turn + 1) % turns.len();
+                self.quota = 0;
+                continue;
+            };
+            if self.quota == 0 {
+                self.quota = self.weights.weight(tenant);
+            }
+            *taken += 1;
+            self.quota -= 1;
+            self.remaining -= 1;
+            if self.quota == 0 {
+                self.turn = (self.turn + 1) % turns.len();
+            }
+            return Some(entry);
+        }
+    }
+}
+
+/// Share of batch slots per tenant relative to the others
+#[derive(Debug, Clone, Default)]
+pub struct TenantWeights(pub HashMap<String, usize>);
+
+impl TenantWeights {
+    /// Weight of `tenant`, 1 unless configured otherwise
+    fn weight(&self, tenant: &str) -> usize {
+        self.0.get(tenant).copied().unwrap_or(1).max(1)
+    }
+
+    fn is_configured(&self, tenant: &str) -> bool {
+        self.0.contains_key(tenant)
+    }
+}
+
+/// Waiting entries and the batching logic, driven by the queue task
+pub(crate) struct QueueState {
+    /// Per length bucket, one lane per priority class indexed by [`Priority::rank`]
+    buckets: Vec<Vec<Lane>>,
     next_id: u64,
     next_batch_id: u64,
+    policy: Arc<dyn BatchPolicy>,
+    config: QueueConfig,
+    /// Shared with [`Queue`], decremented whenever entries leave the queue
+    depth: Arc<AtomicUsize>,
+    /// Tenants reported in the last depth metrics update
+    reported_tenants: HashSet<Arc<str>>,
+    reported_at: Option<Instant>,
+    /// Last time dead entries were evicted from the whole queue
+    swept_at: Option<Instant>,
+}
+
+/// What the policy makes of the entries of one bucket
+struct Candidate {
+    bucket: usize,
+    /// Number of entries taken from the front of every lane
+    taken: Vec<usize>,
+    batch_size: usize,
+    full: bool,
+    oldest: Instant,
+    deadline: Instant,
 }
 
 impl QueueState {
-    pub fn new() -> Self {
+    pub fn new(policy: Arc<dyn BatchPolicy>, config: QueueConfig, depth: Arc<AtomicUsize>) -> Self {
+        let lanes = vec![Lane::default(); Priority::CLASSES];
         Self {
-            entries: VecDeque::with_capacity(64),
+            buckets: vec![lanes; config.bucketing.buckets()],
             next_id: 1,
             next_batch_id: 1,
+            policy,
+            config,
+            depth,
+            reported_tenants: HashSet::new(),
+            reported_at: None,
+            swept_at: None,
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.buckets.iter().flatten().map(Lane::len).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Publish the number of queued entries of every tenant, at most every
+    /// [`TENANT_DEPTH_INTERVAL`] unless the queue ran empty. Only tenants with a
+    /// configured weight and the default tenant get their own series, so that the
+    /// number of series stays bounded.
+    fn report_tenant_depths(&mut self, now: Instant) {
+        if !self.is_empty()
+            && self
+                .reported_at
+                .is_some_and(|at| now - at < TENANT_DEPTH_INTERVAL)
+        {
+            return;
+        }
+        self.reported_at = Some(now);
+        let mut depths: HashMap<Arc<str>, usize> = HashMap::new();
+        for lane in self.buckets.iter().flatten() {
+            for (tenant, entries) in &lane.tenants {
+                let label = if self.config.tenant_weights.is_configured(tenant)
+                    || tenant.as_ref() == DEFAULT_TENANT
+                {
+                    tenant.clone()
+                } else {
+                    Arc::from(OTHER_TENANTS)
+                };
+                *depths.entry(label).or_default() += entries.len();
+            }
+        }
+        for tenant in self.reported_tenants.drain() {
+            if !depths.contains_key(&tenant) {
+                metrics::gauge!("router_tenant_queue_depth", "tenant" => tenant.to_string())
+                    .set(0.0);
+            }
+        }
+        for (tenant, depth) in depths {
+            metrics::gauge!("router_tenant_queue_depth", "tenant" => tenant.to_string())
+                .set(depth as f64);
+            self.reported_tenants.insert(tenant);
         }
     }
 
+    fn lane(&mut self, request: &TextReplyRequest) -> &mut Lane {
+        let bucket = self.config.bucketing.bucket(request);
+        &mut self.buckets[bucket][request.priority.rank()]
+    }
+
     pub fn append(&mut self, entry: QueueEntry) {
-        self.entries.push_back((self.next_id, entry));
+        let id = self.next_id;
+        self.lane(&entry.request).push_back((id, entry));
+        self.next_id += 1;
     }
 
-    #[instrument(skip_all, fields(next_id, next_batch_id))]
-    pub async fn next_batch(&mut self) -> Option<NextBatch> {
-        if self.entries.is_empty() {
-            return None;
+    pub fn requeue(&mut self, mut entries: Vec<(u64, QueueEntry)>) {
+        // Oldest entry ends up in front
+        entries.sort_unstable_by_key(|(id, _)| std::cmp::Reverse(*id));
+        for entry in entries {
+            self.lane(&entry.1.request).push_front(entry);
         }
-        tracing::info!("Gathering batch from {} entries", self.entries.len());
-        let mut batch_entries: HashMap<u32, QueueEntry> =
-            HashMap::with_capacity(self.entries.len());
+    }
+
+    /// Remove every entry, oldest first
+    pub fn drain(&mut self) -> Vec<QueueEntry> {
+        let queued = self.len();
+        let mut entries = Vec::with_capacity(queued);
+        for lane in self.buckets.iter_mut().flatten() {
+            entries.extend(lane.extract(|_| true));
+        }
+        self.sync_depth(queued);
+        entries.sort_unstable_by_key(|(id, _)| *id);
+        entries.into_iter().map(|(_, entry)| entry).collect()
+    }
 
-        let mut batch_requests = Vec::with_capacity(self.entries.len());
+    /// Release the slots of entries removed since the queue held `queued` entries
+    fn sync_depth(&self, queued: usize) {
+        self.depth.fetch_sub(queued - self.len(), Ordering::SeqCst);
+    }
 
-        let i = 0;
-        while i < self.entries.len() {
-            if let Some((_, entry)) = self.entries.get_mut(i) {
-                if entry.response_tx.is_closed() {
-                    // Remove the closed entry from the VecDeque
-                    self.entries.remove(i);
-                    continue;
-                }
+    /// Priority class of an entry in lane `rank`, raised by one for every
+    /// `priority_aging` it has waited
+    fn effective_rank(&self, rank: usize, entry: &QueueEntry, now: Instant) -> usize {
+        let aging = self.config.priority_aging;
+        if aging.is_zero() {
+            return rank;
+        }
+        let promotions = (now - entry.queue_time).as_nanos() / aging.as_nanos();
+        (rank + promotions.min(Priority::CLASSES as u128) as usize).min(Priority::CLASSES - 1)
+    }
+
+    /// Take entries of `bucket` for as long as the policy lets them in, highest
+    /// effective priority first, and in round-robin order across tenants within a class
+    fn candidate(&self, bucket: usize, now: Instant) -> Option<Candidate> {
+        let lanes = &self.buckets[bucket];
+        let oldest = lanes.iter().filter_map(Lane::oldest).min()?;
+
+        let weights = &self.config.tenant_weights;
+        let mut schedules = lanes
+            .iter()
+            .map(|lane| lane.schedule(weights).peekable())
+            .collect::<Vec<_>>();
+        let mut taken = vec![0; lanes.len()];
+        let mut usage = BatchUsage::default();
+        let mut full = false;
+        while let Some(rank) = (0..lanes.len())
+            .filter_map(|rank| schedules[rank].peek().map(|(_, entry)| (rank, entry)))
+            .max_by_key(|(rank, entry)| {
+                (
+                    self.effective_rank(*rank, entry, now),
+                    std::cmp::Reverse(entry.queue_time),
+                )
+            })
+            .map(|(rank, _)| rank)
+        {
+            let (_, entry) = schedules[rank].next().unwrap();
+            let cost = self.policy.cost(&entry.request);
+            if usage.requests > 0 && !self.policy.fits(&usage, cost) {
+                full = true;
+                break;
+            }
+            usage.add(cost);
+            taken[rank] += 1;
+        }
 
-                let req = HttpRequest::new(self.next_id, entry.request.message.clone());
-                batch_requests.push(req);
-                batch_entries.insert(self.next_id as u32, entry.clone());
-                self.next_id += 1;
+        Some(Candidate {
+            bucket,
+            taken,
+            batch_size: usage.requests,
+            full: full || self.policy.is_full(&usage),
+            oldest,
+            deadline: self.policy.deadline(oldest),
+        })
+    }
+
+    #[instrument(skip_all, fields(batch_size, next_batch_id, bucket, coalesced))]
+    pub fn next_batch(&mut self) -> BatchStatus {
+        let now = Instant::now();
+        if self
+            .swept_at
+            .map_or(true, |at| now - at >= EVICTION_INTERVAL)
+        {
+            self.sweep(now);
+        }
+        // Cut until a batch holds a live entry. Entries that died since the last sweep
+        // are not worth sending.
+        let (chosen, entries, taken, coalesced) = loop {
+            let chosen = match self.choose(now) {
+                Ok(chosen) => chosen,
+                Err(status) => return status,
+            };
+
+            let lanes = &mut self.buckets[chosen.bucket];
+            let entries = lanes.iter().map(Lane::len).sum::<usize>();
+
+            let mut taken = Vec::with_capacity(chosen.batch_size);
+            for (lane, &n) in lanes.iter_mut().zip(&chosen.taken).rev() {
+                taken.extend(lane.take(n, &self.config.tenant_weights));
+            }
+            let mut removed = taken.len();
+            let mut coalesced = 0;
+            if self.config.dedup {
+                let pulled;
+                (taken, pulled, coalesced) = coalesce(taken, lanes);
+                removed += pulled;
+            }
+            self.depth.fetch_sub(removed, Ordering::SeqCst);
 
-                // Remove the processed entry from the VecDeque
-                self.entries.remove(i);
+            taken.retain(|(_, entry)| !is_dead(entry, now));
+            if !taken.is_empty() {
+                break (chosen, entries, taken, coalesced);
             }
+        };
+
+        let batch_size = taken.len();
+        tracing::info!(
+            "Gathering batch of {} from {} entries ({} coalesced)",
+            batch_size,
+            entries,
+            coalesced
+        );
+        metrics::counter!("router_coalesced_requests_total").increment(coalesced as u64);
+
+        let mut batch_entries: HashMap<u64, QueueEntry> = HashMap::with_capacity(batch_size);
+        let mut batch_requests = Vec::with_capacity(batch_size);
+        for (id, entry) in taken {
+            batch_requests.push(HttpRequest::new(id, entry.request.message.clone()));
+            batch_entries.insert(id, entry);
         }
 
+        let span = Span::current();
+        span.record("batch_size", batch_size);
+        span.record("coalesced", coalesced);
+        span.record("next_batch_id", self.next_batch_id);
+        span.record("bucket", chosen.bucket);
+
         let batch = ClientBatch::new(
             self.next_batch_id,
             batch_requests.len() as u32,
             batch_requests,
         );
         self.next_batch_id += 1;
+        self.report_tenant_depths(now);
 
-        Some((batch_entries, batch, Span::current()))
+        BatchStatus::Ready((batch_entries, batch, span))
     }
+
+    /// Evict expired entries and entries whose client has already gone away
+    fn sweep(&mut self, now: Instant) {
+        let queued = self.len();
+        for lane in self.buckets.iter_mut().flatten() {
+            lane.retain(|(_, entry)| !is_dead(entry, now));
+        }
+        self.sync_depth(queued);
+        self.swept_at = Some(now);
+    }
+
+    /// The bucket to cut a batch from, or why no batch can be cut
+    fn choose(&self, now: Instant) -> Result<Candidate, BatchStatus> {
+        let candidates = (0..self.buckets.len())
+            .filter_map(|bucket| self.candidate(bucket, now))
+            .collect::<Vec<_>>();
+        if candidates.is_empty() {
+            return Err(BatchStatus::Empty);
+        }
+
+        // Starving buckets go first, then full ones, then the one waiting the longest
+        let starving = |c: &Candidate| now - c.oldest >= self.config.bucketing.starvation;
+        let deadline = candidates.iter().map(|c| c.deadline).min().unwrap();
+        candidates
+            .into_iter()
+            .filter(|c| c.full || now >= c.deadline || starving(c))
+            .max_by_key(|c| {
+                let starving = starving(c);
+                (starving, !starving && c.full, std::cmp::Reverse(c.oldest))
+            })
+            .ok_or(BatchStatus::Pending(deadline))
+    }
+}
+
+/// Whether nobody waits for the response of `entry` anymore. Expired entries are
+/// answered with [`ProcessorError::Timeout`].
+fn is_dead(entry: &QueueEntry, now: Instant) -> bool {
+    if entry.deadline <= now {
+        entry.respond(Err(ProcessorError::Timeout));
+        return true;
+    }
+    if entry.is_abandoned() {
+        // Nobody gets this, but it closes the entry in the write-ahead log
+        entry.respond(Err(ProcessorError::Dropped));
+        return true;
+    }
+    false
+}
+
+/// Merge the entries of a batch that are the same request, i.e. have the same message
+/// and parameters, along with entries of the same request still waiting in `lanes`.
+/// The backend only sees the message, so they all get the same reply.
+///
+/// Returns the merged batch, the number of entries pulled from `lanes` and the number
+/// of entries coalesced into others.
+fn coalesce(
+    batch: Vec<(u64, QueueEntry)>,
+    lanes: &mut [Lane],
+) -> (Vec<(u64, QueueEntry)>, usize, usize) {
+    let mut merged: Vec<(u64, QueueEntry)> = Vec::with_capacity(batch.len());
+    let mut by_request: HashMap<TextReplyRequest, usize> = HashMap::with_capacity(batch.len());
+    let mut coalesced = 0;
+    let mut absorb = |merged: &mut Vec<(u64, QueueEntry)>, (id, entry): (u64, QueueEntry)| {
+        match by_request.get(&entry.request) {
+            Some(&index) => {
+                let leader = &mut merged[index];
+                // The id sent to the backend is the one of the leading entry
+                if entry.deadline > leader.1.deadline {
+                    leader.0 = id;
+                }
+                leader.1.absorb(entry);
+                coalesced += 1;
+            }
+            None => {
+                by_request.insert(entry.request.clone(), merged.len());
+                merged.push((id, entry));
+            }
+        }
+    };
+
+    for entry in batch {
+        absorb(&mut merged, entry);
+    }
+    let mut pulled = 0;
+    let requests = merged
+        .iter()
+        .map(|(_, entry)| entry.request.clone())
+        .collect::<HashSet<_>>();
+    for lane in lanes.iter_mut() {
+        for entry in lane.extract(|entry| requests.contains(&entry.request)) {
+            absorb(&mut merged, entry);
+            pulled += 1;
+        }
+    }
+    (merged, pulled, coalesced)
 }
 
-async fn queue_task(mut queue_receiver: mpsc::UnboundedReceiver<QueueCommand>) {
-    let mut state = QueueState::new();
+async fn queue_task(
+    mut queue_receiver: mpsc::UnboundedReceiver<QueueCommand>,
+    batch_sender: mpsc::UnboundedSender<ReadyBatch>,
+    mut state: QueueState,
+    slots: Arc<Semaphore>,
+) {
+    let mut closed = false;
+    let apply = |state: &mut QueueState, closed: &mut bool, command| match command {
+        QueueCommand::Append(entry, span) => span.in_scope(|| state.append(*entry)),
+        QueueCommand::Requeue(entries, span) => span.in_scope(|| state.requeue(entries)),
+        QueueCommand::Drain(sender) => {
+            let _ = sender.send(state.drain());
+        }
+        QueueCommand::Close => *closed = true,
+    };
+    // In-flight slot for the next batch, held while waiting for the batch to be ready
+    let mut slot: Option<OwnedSemaphorePermit> = None;
+
+    loop {
+        // A slot held for an empty queue would count as a batch out forever
+        if closed || state.is_empty() {
+            slot = None;
+        }
+        // Only cut a batch once it can be dispatched right away, so that requests
+        // keep accumulating in the queue while every slot is busy
+        let mut pending = None;
+        while !closed && !state.is_empty() {
+            let Some(permit) = slot
+                .take()
+                .or_else(|| slots.clone().try_acquire_owned().ok())
+            else {
+                break;
+            };
+            match state.next_batch() {
+                BatchStatus::Ready(next_batch) => {
+                    if batch_sender.send((next_batch, permit)).is_err() {
+                        return;
+                    }
+                }
+                BatchStatus::Pending(deadline) => {
+                    slot = Some(permit);
+                    pending = Some(deadline);
+                    break;
+                }
+                BatchStatus::Empty => break,
+            }
+        }
+        let waiting_for_slot = !closed && slot.is_none() && !state.is_empty();
 
-    while let Some(command) = queue_receiver.recv().await {
-        match command {
-            QueueCommand::Append(entry, span) => {
-                span.in_scope(|| state.append(*entry));
+        tokio::select! {
+            command = queue_receiver.recv() => {
+                let Some(command) = command else { return };
+                apply(&mut state, &mut closed, command);
+                // Take in everything that arrived meanwhile before cutting batches
+                while let Ok(command) = queue_receiver.try_recv() {
+                    apply(&mut state, &mut closed, command);
+                }
             }
-            QueueCommand::NextBatch {
-                response_sender,
-                span: _,
-            } => {
-                let batch = state.next_batch().await;
-                response_sender.send(batch).unwrap();
+            // Wait for the window to close or for new entries that may fill the batch
+            _ = tokio::time::sleep_until(pending.unwrap_or_else(Instant::now)), if pending.is_some() => {}
+            permit = slots.clone().acquire_owned(), if waiting_for_slot => {
+                slot = Some(permit.unwrap());
             }
         }
     }
 }
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::policy::{BatchingConfig, PolicyKind};
+
+    /// Size or timeout batching without a budget
+    pub(crate) fn policy(max_batch_size: usize, max_wait: Duration) -> Arc<dyn BatchPolicy> {
+        BatchingConfig {
+            max_batch_size,
+            max_wait,
+            max_batch_budget: usize::MAX,
+            budget_unit: BudgetUnit::Bytes,
+            latency_target: Duration::from_millis(200),
+        }
+        .policy(PolicyKind::SizeOrTimeout)
+    }
+
+    /// An unbounded queue with a single lane and no aging, weights or dedup
+    pub(crate) fn config() -> QueueConfig {
+        QueueConfig {
+            max_depth: usize::MAX,
+            bucketing: Bucketing {
+                boundaries: Vec::new(),
+                unit: BudgetUnit::Bytes,
+                starvation: Duration::from_secs(1),
+            },
+            priority_aging: Duration::ZERO,
+            tenant_weights: TenantWeights::default(),
+            dedup: false,
+        }
+    }
+
+    pub(crate) fn request(message: &str) -> TextReplyRequest {
+        TextReplyRequest {
+            message: message.to_string(),
+            timeout_ms: None,
+            priority: Priority::Normal,
+            tenant: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn abandoned_entries_are_skipped_without_recursing() {
+        let policy = policy(1, Duration::ZERO);
+        let mut state = QueueState::new(policy, config(), Arc::new(AtomicUsize::new(0)));
+        let now = Instant::now();
+        for i in 0..20_000 {
+            let (entry, _) = QueueEntry::new(
+                request(&format!("message {i}")),
+                now,
+                now + Duration::from_secs(60),
+            );
+            state.append(entry);
+        }
+        // No sweep is due, so the cut has to go through the dead entries one by one
+        state.swept_at = Some(Instant::now());
+        assert!(matches!(state.next_batch(), BatchStatus::Empty));
+        assert!(state.is_empty());
+    }
+
+    #[tokio::test]
+    async fn only_identical_requests_are_coalesced() {
+        let config = QueueConfig {
+            dedup: true,
+            ..config()
+        };
+        let policy = policy(8, Duration::ZERO);
+        let mut state = QueueState::new(policy, config, Arc::new(AtomicUsize::new(0)));
+        let now = Instant::now();
+        let mut receivers = Vec::new();
+        for timeout_ms in [None, Some(5000), None] {
+            let request = TextReplyRequest {
+                timeout_ms,
+                ..request("a")
+            };
+            let (entry, response_rx) = QueueEntry::new(request, now, now + Duration::from_secs(60));
+            state.append(entry);
+            receivers.push(response_rx);
+        }
+        let BatchStatus::Ready((entries, _, _)) = state.next_batch() else {
+            panic!("no batch was cut");
+        };
+        let mut followers = entries
+            .values()
+            .map(|entry| (entry.request.timeout_ms, entry.followers.len()))
+            .collect::<Vec<_>>();
+        followers.sort();
+        assert_eq!(followers, [(None, 1), (Some(5000), 0)]);
+    }
+}
@@ -0,0 +1,111 @@
+use crate::processor::ProcessorError;
+use crate::queue::QueueEntry;
+use crate::TextReplyRequest;
+use std::fs::{self, File};
+use std::io::{self, BufWriter};
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::time::Instant;
+
+/// A request that had no response yet when the router shut down
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+pub struct Pending {
+    pub request: TextReplyRequest,
+    /// Milliseconds since the Unix epoch
+    pub deadline_ms: u64,
+}
+
+impl Pending {
+    pub fn deadline(&self) -> SystemTime {
+        UNIX_EPOCH + Duration::from_millis(self.deadline_ms)
+    }
+}
+
+/// Write the requests of `entries` to a snapshot at `path`, followers included, and
+/// answer their callers. Returns the number of requests written.
+///
+/// The snapshot replaces the file at `path` at once. If it cannot be written, the
+/// entries are dropped without an answer, so that a write-ahead log keeps them.
+pub fn save(path: &Path, entries: Vec<QueueEntry>) -> io::Result<usize> {
+    let now = Instant::now();
+    let wall_now = SystemTime::now();
+    let mut pending = Vec::with_capacity(entries.len());
+    for entry in &entries {
+        for entry in std::iter::once(entry).chain(&entry.followers) {
+            let deadline = wall_now + entry.deadline.saturating_duration_since(now);
+            pending.push(Pending {
+                request: entry.request.clone(),
+                deadline_ms: deadline.duration_since(UNIX_EPOCH).unwrap().as_millis() as u64,
+            });
+        }
+    }
+
+    let partial = path.with_extension("partial");
+    let mut writer = BufWriter::new(File::create(&partial)?);
+    serde_json::to_writer(&mut writer, &pending)?;
+    let file = writer.into_inner().map_err(|e| e.into_error())?;
+    file.sync_all()?;
+    fs::rename(&partial, path)?;
+
+    for entry in entries {
+        entry.respond(Err(ProcessorError::Unavailable(
+            "Router is shutting down, send the request again after the restart".to_string(),
+        )));
+    }
+    Ok(pending.len())
+}
+
+/// Read the snapshot at `path` and remove it, so that its requests are only restored
+/// once. A missing snapshot holds no requests.
+pub fn load(path: &Path) -> io::Result<Vec<Pending>> {
+    let contents = match fs::read(path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+    let pending = serde_json::from_slice(&contents)?;
+    fs::remove_file(path)?;
+    Ok(pending)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::queue::tests::request;
+    use crate::queue::ResponseRx;
+
+    fn entry(message: &str, timeout: Duration) -> (QueueEntry, ResponseRx) {
+        let now = Instant::now();
+        QueueEntry::new(request(message), now, now + timeout)
+    }
+
+    #[tokio::test]
+    async fn saved_requests_are_loaded_once() {
+        let dir = std::env::temp_dir().join(format!("router-snapshot-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("snapshot.json");
+
+        let (mut leader, mut leader_rx) = entry("a", Duration::from_secs(60));
+        let (follower, mut follower_rx) = entry("a", Duration::from_secs(30));
+        leader.followers.push(follower);
+        let (other, mut other_rx) = entry("b", Duration::from_secs(60));
+        assert_eq!(save(&path, vec![leader, other]).unwrap(), 3);
+        for rx in [&mut leader_rx, &mut follower_rx, &mut other_rx] {
+            assert!(matches!(
+                rx.recv().await,
+                Some(Err(ProcessorError::Unavailable(_)))
+            ));
+        }
+
+        let pending = load(&path).unwrap();
+        let messages = pending
+            .iter()
+            .map(|pending| pending.request.message.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(messages, ["a", "a", "b"]);
+        assert!(pending[1].deadline() < pending[0].deadline());
+        assert!(pending[0].deadline() > SystemTime::now() + Duration::from_secs(50));
+        assert!(load(&path).unwrap().is_empty());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,478 @@
+use crate::TextReplyRequest;
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Records written since the last compaction before the log is compacted again,
+/// provided that most of them are done
+const COMPACT_MIN_RECORDS: usize = 4096;
+/// Time after which the writer tries again to write records it failed to write
+const WRITE_RETRY_INTERVAL: Duration = Duration::from_secs(1);
+
+/// One line of the log
+#[derive(serde::Serialize, serde::Deserialize, Debug)]
+#[serde(tag = "op", rename_all = "snake_case")]
+enum Record {
+    /// A request entered the queue
+    Append {
+        id: u64,
+        request: TextReplyRequest,
+        /// Milliseconds since the Unix epoch
+        deadline_ms: u64,
+    },
+    /// The request got its response, or failed for good
+    Done { id: u64 },
+}
+
+/// Write-ahead log of the queue, a file of JSON records, one per line.
+///
+/// Every entry appended to the queue is logged, and marked done once it got its
+/// response. Entries that are not done when the router starts are queued again.
+///
+/// Records are written by a dedicated thread, which also compacts the log, so that
+/// neither the HTTP handlers nor the queue task wait for the disk. Records are not
+/// synced, so the log survives the router process crashing, but not the machine, and
+/// loses the records still on their way to the writer when the process dies.
+#[derive(Debug)]
+pub struct Wal {
+    sender: mpsc::Sender<Command>,
+    next_id: AtomicU64,
+    /// Whether the writer has records it failed to write. Appends are refused meanwhile.
+    failed: Arc<AtomicBool>,
+}
+
+#[derive(Debug)]
+enum Command {
+    Append {
+        id: u64,
+        line: String,
+    },
+    Done {
+        id: u64,
+    },
+    /// Answered once every earlier record is written
+    Flush(mpsc::SyncSender<()>),
+}
+
+/// Owns the log file on the writer thread
+struct Writer {
+    path: PathBuf,
+    file: File,
+    /// Lines of the entries that are not done yet
+    pending: HashMap<u64, String>,
+    /// Records written since the last compaction
+    written: usize,
+    /// Whether a failed write may have left a partial record at the end of the file
+    torn: bool,
+}
+
+/// An entry logged as appended and not done when the log was opened
+#[derive(Debug)]
+pub struct Recovered {
+    pub request: TextReplyRequest,
+    pub deadline: SystemTime,
+    pub ticket: WalTicket,
+}
+
+/// Marks a logged entry as done
+#[derive(Debug, Clone)]
+pub struct WalTicket {
+    id: u64,
+    wal: Arc<Wal>,
+}
+
+impl WalTicket {
+    pub fn done(&self) {
+        // The writer runs for as long as the log, which the ticket keeps alive
+        let _ = self.wal.sender.send(Command::Done { id: self.id });
+    }
+}
+
+impl Wal {
+    /// Open the log at `path`, creating it if needed, and return the entries that are
+    /// not done yet, oldest first. The log is compacted to these entries.
+    pub fn open(path: &Path) -> io::Result<(Arc<Self>, Vec<Recovered>)> {
+        let mut pending = HashMap::new();
+        let mut next_id = 1;
+        match File::open(path) {
+            Ok(file) => {
+                for line in BufReader::new(file).lines() {
+                    let line = line?;
+                    // Left behind by a failed write
+                    if line.is_empty() {
+                        continue;
+                    }
+                    // Only the last record can be torn, by a crash in the middle of a write
+                    let Ok(record) = serde_json::from_str::<Record>(&line) else {
+                        tracing::warn!("Skipping unreadable record in {}", path.display());
+                        continue;
+                    };
+                    match record {
+                        Record::Append { id, .. } => {
+                            next_id = next_id.max(id + 1);
+                            pending.insert(id, line);
+                        }
+                        Record::Done { id } => {
+                            pending.remove(&id);
+                        }
+                    }
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+
+        let file = compact(path, &pending)?;
+        let (sender, receiver) = mpsc::channel();
+        let failed = Arc::new(AtomicBool::new(false));
+        let writer = Writer {
+            path: path.to_path_buf(),
+            file,
+            pending: pending.clone(),
+            written: 0,
+            torn: false,
+        };
+        let writer_failed = failed.clone();
+        thread::Builder::new()
+            .name("wal-writer".to_string())
+            .spawn(move || writer.run(receiver, &writer_failed))?;
+        let wal = Arc::new(Self {
+            sender,
+            next_id: AtomicU64::new(next_id),
+            failed,
+        });
+
+        let mut recovered = pending
+            .into_iter()
+            .map(|(id, line)| {
+                let Ok(Record::Append {
+                    request,
+                    deadline_ms,
+                    ..
+                }) = serde_json::from_str(&line)
+                else {
+                    unreachable!("pending lines are append records");
+                };
+                Recovered {
+                    request,
+                    deadline: UNIX_EPOCH + Duration::from_millis(deadline_ms),
+                    ticket: WalTicket {
+                        id,
+                        wal: wal.clone(),
+                    },
+                }
+            })
+            .collect::<Vec<_>>();
+        recovered.sort_unstable_by_key(|recovered| recovered.ticket.id);
+        Ok((wal, recovered))
+    }
+
+    /// Log a request entering the queue. Fails while the log cannot be written.
+    pub fn append(
+        self: &Arc<Self>,
+        request: &TextReplyRequest,
+        deadline: SystemTime,
+    ) -> io::Result<WalTicket> {
+        if self.failed.load(Ordering::Relaxed) {
+            return Err(io::Error::other("earlier records are not written yet"));
+        }
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let line = serde_json::to_string(&Record::Append {
+            id,
+            request: request.clone(),
+            deadline_ms: deadline
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_millis() as u64,
+        })?;
+        self.sender
+            .send(Command::Append { id, line })
+            .map_err(|_| io::Error::other("the writer is gone"))?;
+        Ok(WalTicket {
+            id,
+            wal: self.clone(),
+        })
+    }
+
+    /// Wait until every record logged so far is written. Blocks the calling thread.
+    pub fn flush(&self) {
+        let (sender, receiver) = mpsc::sync_channel(1);
+        if self.sender.send(Command::Flush(sender)).is_ok() {
+            let _ = receiver.recv();
+        }
+    }
+}
+
+impl Writer {
+    /// Write records until every sender is gone, each burst of them in one call.
+    /// Records that could not be written are tried again with the next burst, or after
+    /// [`WRITE_RETRY_INTERVAL`].
+    fn run(mut self, receiver: mpsc::Receiver<Command>, failed: &AtomicBool) {
+        let mut buffer = Vec::new();
+        let mut flushes = Vec::new();
+        loop {
+            let command = if buffer.is_empty() {
+                receiver.recv().ok()
+            } else {
+                match receiver.recv_timeout(WRITE_RETRY_INTERVAL) {
+                    Ok(command) => Some(command),
+                    Err(mpsc::RecvTimeoutError::Timeout) => None,
+                    Err(mpsc::RecvTimeoutError::Disconnected) => return,
+                }
+            };
+            match command {
+                Some(command) => self.apply(command, &mut buffer, &mut flushes),
+                None if buffer.is_empty() => return,
+                None => {}
+            }
+            while let Ok(command) = receiver.try_recv() {
+                self.apply(command, &mut buffer, &mut flushes);
+            }
+
+            match self.write(&buffer) {
+                Ok(()) => buffer.clear(),
+                Err(e) => tracing::error!("Failed to write to the write-ahead log: {}", e),
+            }
+            failed.store(!buffer.is_empty(), Ordering::Relaxed);
+            for flush in flushes.drain(..) {
+                let _ = flush.send(());
+            }
+        }
+    }
+
+    /// Add the record of `command` to `buffer`
+    fn apply(
+        &mut self,
+        command: Command,
+        buffer: &mut Vec<u8>,
+        flushes: &mut Vec<mpsc::SyncSender<()>>,
+    ) {
+        match command {
+            Command::Append { id, line } => {
+                buffer.extend_from_slice(line.as_bytes());
+                self.pending.insert(id, line);
+            }
+            Command::Done { id } => {
+                if self.pending.remove(&id).is_none() {
+                    return;
+                }
+                serde_json::to_writer(&mut *buffer, &Record::Done { id }).unwrap();
+            }
+            Command::Flush(flush) => {
+                flushes.push(flush);
+                return;
+            }
+        }
+        buffer.push(b'\n');
+        self.written += 1;
+    }
+
+    fn write(&mut self, buffer: &[u8]) -> io::Result<()> {
+        if self.torn {
+            // End the partial record of the failed write, so that it is skipped
+            self.file.write_all(b"\n")?;
+            self.torn = false;
+        }
+        if let Err(e) = self.file.write_all(buffer) {
+            self.torn = true;
+            return Err(e);
+        }
+        // Rewrite the log once it mostly holds entries that are done
+        if self.written >= COMPACT_MIN_RECORDS && self.written >= 4 * self.pending.len() {
+            match compact(&self.path, &self.pending) {
+                Ok(file) => self.file = file,
+                Err(e) => tracing::error!("Failed to compact the write-ahead log: {}", e),
+            }
+            self.written = 0;
+        }
+        Ok(())
+    }
+}
+
+/// Replace the log at `path` with the append records of `pending` and open it for
+/// appending. The new log is moved into place in one step, so a crash leaves either
+/// the old or the new log.
+fn compact(path: &Path, pending: &HashMap<u64, String>) -> io::Result<File> {
+    let mut lines = pending.iter().collect::<Vec<_>>();
+    lines.sort_unstable_by_key(|(id, _)| **id);
+
+    let compacted = path.with_extension("compacting");
+    let mut file = File::create(&compacted)?;
+    for (_, line) in lines {
+        file.write_all(line.as_bytes())?;
+        file.write_all(b"\n")?;
+    }
+    file.sync_all()?;
+    std::fs::rename(&compacted, path)?;
+    OpenOptions::new().append(true).open(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::processor::ProcessorError;
+    use crate::queue::tests::{config, policy};
+    use crate::queue::{Queue, QueueEntry};
+    use crate::Priority;
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+    use std::collections::BTreeSet;
+    use tokio::time::Instant;
+
+    fn temp_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("router-wal-{}-{}", std::process::id(), name));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    fn request(n: u64) -> TextReplyRequest {
+        TextReplyRequest {
+            message: format!("message {n}"),
+            timeout_ms: None,
+            priority: Priority::Low,
+            tenant: Some("bulk".to_string()),
+        }
+    }
+
+    fn deadline() -> SystemTime {
+        SystemTime::now() + Duration::from_secs(3600)
+    }
+
+    fn recovered_messages(recovered: &[Recovered]) -> BTreeSet<String> {
+        recovered
+            .iter()
+            .map(|recovered| recovered.request.message.clone())
+            .collect()
+    }
+
+    /// Append and complete entries at random, kill the queue at a random point,
+    /// possibly in the middle of writing a record, and check that exactly the entries
+    /// that were not done come back
+    #[test]
+    fn pending_entries_survive_a_crash_at_any_point() {
+        let mut rng = StdRng::seed_from_u64(7);
+        let path = temp_path("crash");
+        for round in 0..20 {
+            let _ = std::fs::remove_file(&path);
+            let (wal, recovered) = Wal::open(&path).unwrap();
+            assert!(recovered.is_empty());
+
+            let mut tickets: Vec<(u64, WalTicket)> = Vec::new();
+            let mut expected = BTreeSet::new();
+            let kill_after = rng.gen_range(0..3 * COMPACT_MIN_RECORDS);
+            for n in 0..kill_after as u64 {
+                if tickets.is_empty() || rng.gen_bool(0.55) {
+                    tickets.push((n, wal.append(&request(n), deadline()).unwrap()));
+                    expected.insert(format!("message {n}"));
+                } else {
+                    let (n, ticket) = tickets.swap_remove(rng.gen_range(0..tickets.len()));
+                    ticket.done();
+                    expected.remove(&format!("message {n}"));
+                }
+            }
+            // The process dies without any cleanup, once the records reached the file
+            wal.flush();
+            drop(tickets);
+            drop(wal);
+            if rng.gen_bool(0.5) {
+                let torn = serde_json::to_string(&Record::Append {
+                    id: u64::MAX,
+                    request: request(u64::MAX),
+                    deadline_ms: 0,
+                })
+                .unwrap();
+                let cut = rng.gen_range(1..torn.len());
+                let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+                file.write_all(&torn.as_bytes()[..cut]).unwrap();
+            }
+
+            let (wal, recovered) = Wal::open(&path).unwrap();
+            assert_eq!(recovered_messages(&recovered), expected, "round {round}");
+
+            // A second restart without progress in between recovers the same entries
+            drop((wal, recovered));
+            let (_, recovered) = Wal::open(&path).unwrap();
+            assert_eq!(recovered_messages(&recovered), expected, "round {round}");
+        }
+        let _ = std::fs::remove_file(&path);
+    }
+
+    /// Deliver a random number of batches, kill the queue with the rest still waiting,
+    /// and check that the log brings back what was not delivered
+    #[tokio::test]
+    async fn undelivered_queue_entries_are_recovered() {
+        let policy = policy(8, Duration::from_millis(1));
+        let config = config();
+
+        let mut rng = StdRng::seed_from_u64(11);
+        let path = temp_path("queue");
+        for round in 0..20 {
+            let _ = std::fs::remove_file(&path);
+            let (wal, _) = Wal::open(&path).unwrap();
+            let (queue, mut batches) =
+                Queue::new(policy.clone(), config.clone(), 1, Some(wal.clone()));
+
+            let requests = rng.gen_range(1..100);
+            let mut receivers = Vec::new();
+            for n in 0..requests {
+                let now = Instant::now();
+                let (entry, response_rx) =
+                    QueueEntry::new(request(n), now, now + Duration::from_secs(3600));
+                queue.append(entry).unwrap();
+                receivers.push(response_rx);
+            }
+
+            let mut expected = (0..requests)
+                .map(|n| format!("message {n}"))
+                .collect::<BTreeSet<_>>();
+            for _ in 0..rng.gen_range(0..=requests.div_ceil(8)) {
+                let ((entries, _, _), _permit) = batches.recv().await.unwrap();
+                for entry in entries.into_values() {
+                    expected.remove(&entry.request.message);
+                    entry.respond(Err(ProcessorError::MissingResponse));
+                }
+            }
+            // Callers keep waiting while the queue dies
+            wal.flush();
+            drop((wal, queue, batches));
+
+            let (_, recovered) = Wal::open(&path).unwrap();
+            assert_eq!(recovered_messages(&recovered), expected, "round {round}");
+            drop(receivers);
+        }
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn recovered_entries_are_done_once_answered() {
+        let path = temp_path("recover");
+        let (wal, _) = Wal::open(&path).unwrap();
+        for n in 0..10 {
+            wal.append(&request(n), deadline()).unwrap();
+        }
+        wal.flush();
+        drop(wal);
+
+        let (wal, recovered) = Wal::open(&path).unwrap();
+        assert_eq!(recovered.len(), 10);
+        // Ids keep counting up after a restart
+        let ticket = wal.append(&request(10), deadline()).unwrap();
+        assert_eq!(ticket.id, 11);
+        for recovered in recovered.iter().step_by(2) {
+            recovered.ticket.done();
+        }
+        ticket.done();
+        wal.flush();
+        drop((wal, recovered));
+
+        let (_, recovered) = Wal::open(&path).unwrap();
+        let messages = recovered_messages(&recovered);
+        let expected = (1..10).step_by(2).map(|n| format!("message {n}")).collect();
+        assert_eq!(messages, expected);
+        let _ = std::fs::remove_file(&path);
+    }
+}
@@ -6,12 +6,12 @@ mod pb {
 
 use clap::Parser;
 use pb::reply::v1::reply_service_server::{ReplyService, ReplyServiceServer};
-use pb::reply::v1::{ReplyRequest, ReplyResponse};
+use pb::reply::v1::{ReplyRequest, ReplyResponse, RequestError};
 use tonic::transport::Server;
 
 use anyhow::Result;
 use std::time::Instant;
-use tonic::{Request, Response, Status};
+use tonic::{Code, Request, Response, Status};
 use tracing::instrument;
 
 struct MyReplyService;
@@ -29,10 +29,22 @@ impl ReplyService for MyReplyService {
 
         let mut responses = vec![];
         for request in batch.requests.iter() {
-            responses.push(pb::reply::v1::Response {
-                request_id: request.id,
-                message: format!("Response for [{}]", request.message),
-            });
+            let response = match reply_to(&request.message) {
+                Ok(message) => pb::reply::v1::Response {
+                    request_id: request.id,
+                    message,
+                    error: None,
+                },
+                Err(status) => pb::reply::v1::Response {
+                    request_id: request.id,
+                    message: String::new(),
+                    error: Some(RequestError {
+                        code: status.code() as i32,
+                        message: status.message().to_string(),
+                    }),
+                },
+            };
+            responses.push(response);
         }
 
         let elapsed_time = start_time.elapsed().as_secs_f32();
@@ -50,6 +62,14 @@ impl ReplyService for MyReplyService {
     }
 }
 
+/// Reply to a single message. Failing one message does not fail the batch.
+fn reply_to(message: &str) -> Result<String, Status> {
+    if message.trim().is_empty() {
+        return Err(Status::new(Code::InvalidArgument, "Message is empty"));
+    }
+    Ok(format!("Response for [{}]", message))
+}
+
 #[derive(Parser, Debug)]
 struct Args {
     #[clap(short, long, default_value = "50051")]
@@ -67,6 +87,11 @@ async fn main() -> Result<()> {
 
     let addr = format!("[::]:{}", args.port).parse()?;
 
+    let (mut health_reporter, health_service) = tonic_health::server::health_reporter();
+    health_reporter
+        .set_serving::<ReplyServiceServer<MyReplyService>>()
+        .await;
+
     let reply_service = MyReplyService {};
     let reflection_service = tonic_reflection::server::Builder::configure()
         .register_encoded_file_descriptor_set(pb::FILE_DESCRIPTOR_SET)
@@ -74,6 +99,7 @@ async fn main() -> Result<()> {
 
     Server::builder()
         .add_service(ReplyServiceServer::new(reply_service))
+        .add_service(health_service)
         .add_service(reflection_service)
         .serve(addr)
         .await?;
@@ -107,7 +107,11 @@ async fn bot_msg_handler(bot: Bot, msg: Message, client: Arc<HttpClient>) -> Res
     let text = msg.text().unwrap();
 
     let response = client
-        .send_request(serde_json::json!({"message": text}))
+        .send_request(serde_json::json!({
+            "message": text,
+            "priority": "high",
+            "tenant": msg.chat.id.to_string(),
+        }))
         .await?;
 
     let reply_msg = bot